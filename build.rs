@@ -1,9 +1,14 @@
+use std::env;
 use std::io::Result;
+use std::path::PathBuf;
 
 fn main() -> Result<()> {
+    // Embedded in the binary and printed by `nockchain-rpc print-descriptor`
+    let descriptor_path = PathBuf::from(env::var("OUT_DIR").unwrap()).join("nockchain_descriptor.bin");
     tonic_build::configure()
+        .file_descriptor_set_path(descriptor_path)
         .build_server(true)
-        .build_client(false) // Only need server for now
+        .build_client(env::var_os("CARGO_FEATURE_CLIENT").is_some()) // Only with the `client` feature
         .compile_protos(&["protos/nockchain.proto"], &["protos"])?;
     Ok(())
 }
@@ -0,0 +1,490 @@
+use std::env;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::time::Duration;
+
+use crate::denomination::{self, Rounding};
+use crate::interceptor::{self, Cidr};
+use crate::service;
+use crate::wallet::{self, AssetFormat};
+
+pub const DEFAULT_HOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+pub const DEFAULT_PORT: u16 = 3000;
+pub const DEFAULT_COMMAND_TIMEOUT_SECS: u64 = 120;
+pub const DEFAULT_HTTP2_KEEPALIVE_INTERVAL_SECS: u64 = 60;
+pub const DEFAULT_HTTP2_KEEPALIVE_TIMEOUT_SECS: u64 = 20;
+pub const DEFAULT_TCP_KEEPALIVE_SECS: u64 = 60;
+pub const DEFAULT_BALANCE_DECIMALS: u64 = 8;
+pub const DEFAULT_BALANCE_POLL_SECS: u64 = 10;
+pub const DEFAULT_MAX_BALANCE_STREAMS: u64 = 100;
+pub const DEFAULT_SHUTDOWN_GRACE_SECS: u64 = 10;
+
+// Where an effective setting came from, for the startup summary
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Flag,
+    Env,
+    Default,
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::Flag => write!(f, "flag"),
+            Source::Env => write!(f, "env"),
+            Source::Default => write!(f, "default"),
+        }
+    }
+}
+
+// All server settings, resolved once at startup.
+// Precedence for each setting is: CLI flag > environment variable > default.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub host: IpAddr,
+    pub host_source: Source,
+    pub port: u16,
+    pub port_source: Source,
+    // Serve on this Unix domain socket instead of HOST:PORT
+    pub uds_path: Option<String>,
+    pub nockchain_socket: Option<String>,
+    pub command_timeout: Duration,
+    // How long in-flight requests may drain on shutdown before wallets are killed
+    pub shutdown_grace: Duration,
+    // Bounds each RPC as a whole; None leaves RPCs unbounded
+    pub request_timeout: Option<Duration>,
+    pub allowed_cidrs: Vec<Cidr>,
+    pub api_token: Option<String>,
+    pub worker_threads: Option<usize>,
+    pub max_blocking_threads: Option<usize>,
+    // None disables the corresponding keepalive
+    pub http2_keepalive_interval: Option<Duration>,
+    pub http2_keepalive_timeout: Duration,
+    pub tcp_keepalive: Option<Duration>,
+    // Spawn the wallet with only WALLET_ENV_VARS instead of inheriting our environment
+    pub wallet_clear_env: bool,
+    // Reject wallet output that isn't valid UTF-8 instead of replacing bad bytes
+    pub wallet_utf8_strict: bool,
+    // Include a failed wallet's stderr in error statuses sent to clients
+    pub debug_errors: bool,
+    // Line formats recognized in wallet output, in match order
+    pub wallet_formats: Vec<&'static AssetFormat>,
+    // Log full detail for 1 in N requests; None disables sampling
+    pub log_sample_rate: Option<u64>,
+    // RPCs to serve; None serves all of them
+    pub enabled_methods: Option<Vec<String>>,
+    // How often each SubscribeBalance stream re-queries the wallet
+    pub balance_poll_interval: Duration,
+    // SubscribeBalance streams allowed open at once
+    pub max_balance_streams: usize,
+    // Raw assets in one nock, for GetBalance and Convert
+    pub assets_per_nock: u64,
+    // How GetBalance rounds nocks, and to how many decimal places
+    pub balance_rounding: Rounding,
+    pub balance_decimals: u32,
+}
+
+// Values given on the command line, e.g. `--port 4000` or `--host=0.0.0.0`
+#[derive(Debug, Default)]
+struct Flags {
+    host: Option<String>,
+    port: Option<String>,
+}
+
+impl Flags {
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut flags = Flags::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (name, inline_value) = match arg.split_once('=') {
+                Some((name, value)) => (name.to_string(), Some(value.to_string())),
+                None => (arg, None),
+            };
+            let slot = match name.as_str() {
+                "--host" => &mut flags.host,
+                "--port" => &mut flags.port,
+                _ => return Err(format!("Unknown argument: {}", name)),
+            };
+            let value = match inline_value {
+                Some(value) => value,
+                None => args.next().ok_or_else(|| format!("Missing value for {}", name))?,
+            };
+            *slot = Some(value);
+        }
+        Ok(flags)
+    }
+}
+
+// Reads a setting by name: the process environment, or a fixed map in tests
+type Lookup<'a> = &'a dyn Fn(&str) -> Option<String>;
+
+// Strip surrounding whitespace and a leading BOM, which copy-paste and some
+// editors leave in .env files. The raw value is logged when anything changes.
+fn env_path(env: Lookup, name: &str) -> Option<String> {
+    let raw = env(name)?;
+    let sanitized = raw.trim().trim_start_matches('\u{feff}').trim().to_string();
+    if sanitized != raw {
+        log::warn!("Sanitized {}: raw value {:?}, using {:?}", name, raw, sanitized);
+    }
+    Some(sanitized).filter(|path| !path.is_empty())
+}
+
+// Pick the flag value, then the env var, and remember which one won
+fn resolve(env: Lookup, flag: Option<String>, env_name: &str) -> Option<(String, Source)> {
+    flag.map(|value| (value, Source::Flag))
+        .or_else(|| env(env_name).map(|value| (value, Source::Env)))
+}
+
+fn parse_thread_count(env: Lookup, name: &str) -> Result<Option<usize>, String> {
+    match env(name) {
+        Some(value) => match value.parse::<usize>() {
+            Ok(0) | Err(_) => Err(format!("Invalid {}: expected a positive integer, got '{}'", name, value)),
+            Ok(count) => Ok(Some(count)),
+        },
+        None => Ok(None),
+    }
+}
+
+fn parse_bool(env: Lookup, name: &str, default: bool) -> Result<bool, String> {
+    match env(name).as_deref().map(str::to_ascii_lowercase).as_deref() {
+        Some("1" | "true" | "yes" | "on") => Ok(true),
+        Some("0" | "false" | "no" | "off") => Ok(false),
+        Some(value) => Err(format!("Invalid {}: expected true or false, got '{}'", name, value)),
+        None => Ok(default),
+    }
+}
+
+fn parse_u64(env: Lookup, name: &str, default: u64) -> Result<u64, String> {
+    match env(name) {
+        Some(value) => value.parse::<u64>().map_err(|e| format!("Invalid {}: {}", name, e)),
+        None => Ok(default),
+    }
+}
+
+// Seconds where 0 turns the setting off
+fn parse_optional_secs(env: Lookup, name: &str, default: u64) -> Result<Option<Duration>, String> {
+    let secs = parse_u64(env, name, default)?;
+    Ok((secs > 0).then(|| Duration::from_secs(secs)))
+}
+
+fn parse_wallet_formats(env: Lookup) -> Result<Vec<&'static AssetFormat>, String> {
+    let value = env("WALLET_OUTPUT_FORMATS");
+    let names: Vec<&str> = match &value {
+        Some(value) => value.split(',').map(str::trim).filter(|name| !name.is_empty()).collect(),
+        None => wallet::DEFAULT_ASSET_FORMATS.to_vec(),
+    };
+    if names.is_empty() {
+        return Err("Invalid WALLET_OUTPUT_FORMATS: no formats given".to_string());
+    }
+
+    names
+        .into_iter()
+        .map(|name| {
+            wallet::find_asset_format(name).ok_or_else(|| {
+                let known: Vec<&str> = wallet::ASSET_FORMATS.iter().map(|format| format.name).collect();
+                format!("Invalid WALLET_OUTPUT_FORMATS: unknown format '{}' (known: {})", name, known.join(", "))
+            })
+        })
+        .collect()
+}
+
+fn parse_enabled_methods(env: Lookup) -> Result<Option<Vec<String>>, String> {
+    let Some(value) = env("ENABLED_METHODS") else {
+        return Ok(None);
+    };
+
+    let methods: Vec<String> = value
+        .split(',')
+        .map(str::trim)
+        .filter(|method| !method.is_empty())
+        .map(str::to_string)
+        .collect();
+    if let Some(unknown) = methods.iter().find(|method| !service::METHODS.contains(&method.as_str())) {
+        return Err(format!(
+            "Invalid ENABLED_METHODS: unknown method '{}' (known: {})",
+            unknown,
+            service::METHODS.join(", ")
+        ));
+    }
+    Ok(Some(methods))
+}
+
+impl Config {
+    // Load from the process arguments and environment
+    pub fn load() -> Result<Self, String> {
+        Self::from_sources(env::args().skip(1), &|name| env::var(name).ok())
+    }
+
+    pub fn from_sources(args: impl IntoIterator<Item = String>, env: Lookup) -> Result<Self, String> {
+        let flags = Flags::parse(args)?;
+
+        let (host, host_source) = match resolve(env, flags.host, "HOST") {
+            Some((value, source)) => (
+                value.parse::<IpAddr>().map_err(|e| format!("Invalid host ({}): {}", source, e))?,
+                source,
+            ),
+            None => (DEFAULT_HOST, Source::Default),
+        };
+
+        let (port, port_source) = match resolve(env, flags.port, "PORT") {
+            Some((value, source)) => (
+                value.parse::<u16>().map_err(|e| format!("Invalid port ({}): {}", source, e))?,
+                source,
+            ),
+            None => (DEFAULT_PORT, Source::Default),
+        };
+
+        let command_timeout = Duration::from_secs(parse_u64(env, "COMMAND_TIMEOUT_SECS", DEFAULT_COMMAND_TIMEOUT_SECS)?);
+
+        let allowed_cidrs = match env("ALLOWED_CIDRS") {
+            Some(value) => interceptor::parse_cidr_list(&value).map_err(|e| format!("Invalid ALLOWED_CIDRS: {}", e))?,
+            None => Vec::new(),
+        };
+
+        let uds_path = env_path(env, "UDS_PATH");
+        // Unix socket peers have no IP address, so every request would be rejected
+        if uds_path.is_some() && !allowed_cidrs.is_empty() {
+            return Err("ALLOWED_CIDRS cannot be used with UDS_PATH, restrict the socket file's permissions instead".to_string());
+        }
+
+        Ok(Config {
+            host,
+            host_source,
+            port,
+            port_source,
+            uds_path,
+            nockchain_socket: env_path(env, "NOCKCHAIN_SOCKET"),
+            command_timeout,
+            shutdown_grace: Duration::from_secs(parse_u64(env, "SHUTDOWN_GRACE_SECS", DEFAULT_SHUTDOWN_GRACE_SECS)?),
+            request_timeout: parse_optional_secs(env, "REQUEST_TIMEOUT_SECS", 0)?,
+            allowed_cidrs,
+            api_token: env("API_TOKEN").filter(|token| !token.is_empty()),
+            worker_threads: parse_thread_count(env, "WORKER_THREADS")?,
+            max_blocking_threads: parse_thread_count(env, "MAX_BLOCKING_THREADS")?,
+            http2_keepalive_interval: parse_optional_secs(
+                env,
+                "HTTP2_KEEPALIVE_INTERVAL_SECS",
+                DEFAULT_HTTP2_KEEPALIVE_INTERVAL_SECS,
+            )?,
+            http2_keepalive_timeout: Duration::from_secs(parse_u64(
+                env,
+                "HTTP2_KEEPALIVE_TIMEOUT_SECS",
+                DEFAULT_HTTP2_KEEPALIVE_TIMEOUT_SECS,
+            )?),
+            tcp_keepalive: parse_optional_secs(env, "TCP_KEEPALIVE_SECS", DEFAULT_TCP_KEEPALIVE_SECS)?,
+            wallet_clear_env: parse_bool(env, "WALLET_CLEAR_ENV", false)?,
+            wallet_utf8_strict: parse_bool(env, "WALLET_UTF8_STRICT", false)?,
+            debug_errors: parse_bool(env, "DEBUG_ERRORS", false)?,
+            wallet_formats: parse_wallet_formats(env)?,
+            log_sample_rate: Some(parse_u64(env, "LOG_SAMPLE_RATE", 0)?).filter(|rate| *rate > 0),
+            enabled_methods: parse_enabled_methods(env)?,
+            balance_poll_interval: match parse_u64(env, "BALANCE_POLL_SECS", DEFAULT_BALANCE_POLL_SECS)? {
+                0 => return Err("Invalid BALANCE_POLL_SECS: must be at least 1".to_string()),
+                secs => Duration::from_secs(secs),
+            },
+            max_balance_streams: match parse_u64(env, "MAX_BALANCE_STREAMS", DEFAULT_MAX_BALANCE_STREAMS)? {
+                0 => return Err("Invalid MAX_BALANCE_STREAMS: must be at least 1".to_string()),
+                streams => streams as usize,
+            },
+            assets_per_nock: match parse_u64(env, "ASSETS_PER_NOCK", denomination::ASSETS_PER_NOCK)? {
+                0 => return Err("Invalid ASSETS_PER_NOCK: must be at least 1".to_string()),
+                assets_per_nock => assets_per_nock,
+            },
+            balance_rounding: match env("BALANCE_ROUNDING") {
+                Some(value) => Rounding::parse(&value).map_err(|e| format!("Invalid BALANCE_ROUNDING: {}", e))?,
+                None => Rounding::None,
+            },
+            balance_decimals: match parse_u64(env, "BALANCE_DECIMALS", DEFAULT_BALANCE_DECIMALS)? {
+                decimals if decimals <= denomination::MAX_ROUNDING_DECIMALS as u64 => decimals as u32,
+                decimals => {
+                    return Err(format!(
+                        "Invalid BALANCE_DECIMALS: {} is more than {}",
+                        decimals,
+                        denomination::MAX_ROUNDING_DECIMALS
+                    ))
+                }
+            },
+        })
+    }
+
+    pub fn listen_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.host, self.port)
+    }
+
+    pub fn method_enabled(&self, method: &str) -> bool {
+        match &self.enabled_methods {
+            Some(methods) => methods.iter().any(|enabled| enabled == method),
+            None => true,
+        }
+    }
+
+    // Names of the optional features this config turns on, for GetServerInfo
+    pub fn features(&self) -> Vec<String> {
+        let mut features = Vec::new();
+        if !self.allowed_cidrs.is_empty() {
+            features.push("allowlist".to_string());
+        }
+        if self.api_token.is_some() {
+            features.push("auth".to_string());
+        }
+        if self.debug_errors {
+            features.push("debug_errors".to_string());
+        }
+        features
+    }
+
+    // One line describing the effective settings, logged at startup
+    pub fn summary(&self) -> String {
+        format!(
+            "listen={}, socket={}, command_timeout={}s, request_timeout={}, shutdown_grace={}s, \
+             allowed_cidrs={}, auth={}, \
+             http2_keepalive={}, tcp_keepalive={}, wallet_env={}, wallet_utf8={}, errors={}, wallet_formats={}, methods={}, \
+             balance_poll={}s, max_streams={}, assets_per_nock={}, rounding={}",
+            match &self.uds_path {
+                Some(path) => format!("unix:{}", path),
+                None => format!("{} (host from {}, port from {})", self.listen_addr(), self.host_source, self.port_source),
+            },
+            self.nockchain_socket.as_deref().unwrap_or("<unset>"),
+            self.command_timeout.as_secs(),
+            match self.request_timeout {
+                Some(limit) => format!("{}s", limit.as_secs()),
+                None => "off".to_string(),
+            },
+            self.shutdown_grace.as_secs(),
+            if self.allowed_cidrs.is_empty() {
+                "all".to_string()
+            } else {
+                self.allowed_cidrs.iter().map(|cidr| cidr.to_string()).collect::<Vec<_>>().join(",")
+            },
+            if self.api_token.is_some() { "token" } else { "off" },
+            match self.http2_keepalive_interval {
+                Some(interval) => format!("{}s/{}s", interval.as_secs(), self.http2_keepalive_timeout.as_secs()),
+                None => "off".to_string(),
+            },
+            match self.tcp_keepalive {
+                Some(keepalive) => format!("{}s", keepalive.as_secs()),
+                None => "off".to_string(),
+            },
+            if self.wallet_clear_env { "isolated" } else { "inherited" },
+            if self.wallet_utf8_strict { "strict" } else { "lossy" },
+            if self.debug_errors { "debug" } else { "generic" },
+            self.wallet_formats.iter().map(|format| format.name).collect::<Vec<_>>().join(","),
+            match &self.enabled_methods {
+                Some(methods) => methods.join(","),
+                None => "all".to_string(),
+            },
+            self.balance_poll_interval.as_secs(),
+            self.max_balance_streams,
+            self.assets_per_nock,
+            match self.balance_rounding {
+                Rounding::None => "none".to_string(),
+                rounding => format!("{}({})", rounding.name(), self.balance_decimals),
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn load(args: &[&str], vars: &[(&str, &str)]) -> Result<Config, String> {
+        let vars: HashMap<String, String> =
+            vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        Config::from_sources(args.iter().map(|arg| arg.to_string()), &|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn flag_beats_env() {
+        let config = load(&["--host", "0.0.0.0", "--port", "4000"], &[("HOST", "10.0.0.1"), ("PORT", "5000")]).unwrap();
+        assert_eq!(config.listen_addr(), "0.0.0.0:4000".parse().unwrap());
+        assert_eq!((config.host_source, config.port_source), (Source::Flag, Source::Flag));
+    }
+
+    #[test]
+    fn env_beats_default() {
+        let config = load(&[], &[("PORT", "5000")]).unwrap();
+        assert_eq!(config.port, 5000);
+        assert_eq!(config.port_source, Source::Env);
+        assert_eq!(config.host, DEFAULT_HOST);
+        assert_eq!(config.host_source, Source::Default);
+    }
+
+    #[test]
+    fn defaults_only() {
+        let config = load(&[], &[]).unwrap();
+        assert_eq!(config.listen_addr(), SocketAddr::new(DEFAULT_HOST, DEFAULT_PORT));
+        assert_eq!((config.host_source, config.port_source), (Source::Default, Source::Default));
+        assert_eq!(config.command_timeout, Duration::from_secs(DEFAULT_COMMAND_TIMEOUT_SECS));
+    }
+
+    #[test]
+    fn inline_flag_values() {
+        let config = load(&["--port=4000", "--host=::1"], &[("PORT", "5000")]).unwrap();
+        assert_eq!(config.listen_addr(), "[::1]:4000".parse().unwrap());
+    }
+
+    #[test]
+    fn bad_arguments_are_rejected() {
+        assert_eq!(load(&["--port"], &[]).unwrap_err(), "Missing value for --port");
+        assert_eq!(load(&["--verbose"], &[]).unwrap_err(), "Unknown argument: --verbose");
+        assert!(load(&["--port", "nope"], &[]).unwrap_err().contains("(flag)"));
+        assert!(load(&[], &[("PORT", "70000")]).unwrap_err().contains("(env)"));
+    }
+
+    #[test]
+    fn thread_counts() {
+        let config = load(&[], &[]).unwrap();
+        assert_eq!((config.worker_threads, config.max_blocking_threads), (None, None));
+
+        let config = load(&[], &[("WORKER_THREADS", "4"), ("MAX_BLOCKING_THREADS", "64")]).unwrap();
+        assert_eq!((config.worker_threads, config.max_blocking_threads), (Some(4), Some(64)));
+
+        for value in ["0", "-1", "four"] {
+            let error = load(&[], &[("WORKER_THREADS", value)]).unwrap_err();
+            assert!(error.starts_with("Invalid WORKER_THREADS"), "{}", error);
+        }
+        assert!(load(&[], &[("MAX_BLOCKING_THREADS", "0")]).is_err());
+    }
+
+    #[test]
+    fn keepalive_settings() {
+        let config = load(&[], &[]).unwrap();
+        assert_eq!(config.http2_keepalive_interval, Some(Duration::from_secs(DEFAULT_HTTP2_KEEPALIVE_INTERVAL_SECS)));
+        assert_eq!(config.http2_keepalive_timeout, Duration::from_secs(DEFAULT_HTTP2_KEEPALIVE_TIMEOUT_SECS));
+        assert_eq!(config.tcp_keepalive, Some(Duration::from_secs(DEFAULT_TCP_KEEPALIVE_SECS)));
+
+        let vars = [("HTTP2_KEEPALIVE_INTERVAL_SECS", "0"), ("TCP_KEEPALIVE_SECS", "0")];
+        let config = load(&[], &vars).unwrap();
+        assert_eq!((config.http2_keepalive_interval, config.tcp_keepalive), (None, None));
+        assert!(config.summary().contains("http2_keepalive=off, tcp_keepalive=off"));
+
+        let config = load(&[], &[("HTTP2_KEEPALIVE_INTERVAL_SECS", "30"), ("HTTP2_KEEPALIVE_TIMEOUT_SECS", "5")]).unwrap();
+        assert_eq!(config.http2_keepalive_interval, Some(Duration::from_secs(30)));
+        assert_eq!(config.http2_keepalive_timeout, Duration::from_secs(5));
+        assert!(load(&[], &[("TCP_KEEPALIVE_SECS", "soon")]).is_err());
+    }
+
+    #[test]
+    fn paths_are_sanitized() {
+        let config = load(&[], &[("NOCKCHAIN_SOCKET", "\u{feff} /run/nockchain.sock\n")]).unwrap();
+        assert_eq!(config.nockchain_socket.as_deref(), Some("/run/nockchain.sock"));
+
+        let config = load(&[], &[("NOCKCHAIN_SOCKET", " \u{feff} "), ("UDS_PATH", "")]).unwrap();
+        assert_eq!((config.nockchain_socket, config.uds_path), (None, None));
+    }
+
+    #[test]
+    fn balance_stream_cap() {
+        assert_eq!(load(&[], &[]).unwrap().max_balance_streams, DEFAULT_MAX_BALANCE_STREAMS as usize);
+        assert_eq!(load(&[], &[("MAX_BALANCE_STREAMS", "3")]).unwrap().max_balance_streams, 3);
+        assert!(load(&[], &[("MAX_BALANCE_STREAMS", "0")]).unwrap_err().contains("at least 1"));
+    }
+
+    #[test]
+    fn assets_per_nock_must_be_positive() {
+        assert_eq!(load(&[], &[]).unwrap().assets_per_nock, denomination::ASSETS_PER_NOCK);
+        assert_eq!(load(&[], &[("ASSETS_PER_NOCK", "100")]).unwrap().assets_per_nock, 100);
+        assert_eq!(load(&[], &[("ASSETS_PER_NOCK", "0")]).unwrap_err(), "Invalid ASSETS_PER_NOCK: must be at least 1");
+    }
+}
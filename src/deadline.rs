@@ -0,0 +1,174 @@
+use tokio::time::{Duration, Instant};
+use tonic::metadata::MetadataMap;
+use tonic::service::Interceptor;
+use tonic::{Request, Status};
+
+// When the request reached the server, stamped by ReceivedAtLayer
+#[derive(Debug, Clone, Copy)]
+pub struct ReceivedAt(pub Instant);
+
+// Stamps each request with ReceivedAt. tonic enforces `grpc-timeout` by
+// dropping the handler with CANCELLED, and starts its timer just after
+// calling the layers inside it. Installed with Server::layer, this stamp is
+// taken first, so a deadline counted from it never expires later than
+// tonic's and the handler gets to answer DEADLINE_EXCEEDED itself.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReceivedAtLayer;
+
+impl Interceptor for ReceivedAtLayer {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        request.extensions_mut().insert(ReceivedAt(Instant::now()));
+        Ok(request)
+    }
+}
+
+// When the request arrived, or now if it was never stamped
+pub fn received_at<T>(request: &Request<T>) -> Instant {
+    match request.extensions().get::<ReceivedAt>() {
+        Some(ReceivedAt(received)) => *received,
+        None => Instant::now(),
+    }
+}
+
+// Read the client's deadline from the `grpc-timeout` metadata, e.g. `500m` or `30S`.
+// Malformed values are ignored, matching how tonic's server treats them.
+pub fn client_timeout(metadata: &MetadataMap) -> Option<Duration> {
+    let value = metadata.get("grpc-timeout")?.to_str().ok()?;
+    if value.len() < 2 {
+        return None;
+    }
+
+    let (amount, unit) = value.split_at(value.len() - 1);
+    // The gRPC spec caps the value at 8 digits, which also rules out overflow below
+    if amount.len() > 8 {
+        return None;
+    }
+    let amount: u64 = amount.parse().ok()?;
+
+    match unit {
+        "H" => Some(Duration::from_secs(amount * 60 * 60)),
+        "M" => Some(Duration::from_secs(amount * 60)),
+        "S" => Some(Duration::from_secs(amount)),
+        "m" => Some(Duration::from_millis(amount)),
+        "u" => Some(Duration::from_micros(amount)),
+        "n" => Some(Duration::from_nanos(amount)),
+        _ => None,
+    }
+}
+
+// When the client's deadline expires, counted from when the request arrived
+pub fn client_deadline<T>(request: &Request<T>) -> Option<Instant> {
+    client_timeout(request.metadata()).map(|timeout| received_at(request) + timeout)
+}
+
+// When the whole RPC times out: at the client deadline, or REQUEST_TIMEOUT_SECS
+// after arrival, whichever comes first
+pub fn rpc_deadline<T>(request: &Request<T>, request_timeout: Option<Duration>) -> Option<Instant> {
+    let server = request_timeout.map(|limit| received_at(request) + limit);
+    match (client_deadline(request), server) {
+        (Some(client), Some(server)) => Some(client.min(server)),
+        (client, server) => client.or(server),
+    }
+}
+
+// When the work for a request must stop: the client deadline or
+// `server_timeout` from now, whichever comes first. An absolute deadline, so
+// time spent before the timer starts isn't added on top.
+pub fn effective_deadline<T>(request: &Request<T>, server_timeout: Duration) -> Instant {
+    let server = Instant::now() + server_timeout;
+    match client_deadline(request) {
+        Some(client) if client < server => {
+            log::debug!("Using client deadline {:?} instead of server timeout {:?}", client, server_timeout);
+            client
+        }
+        _ => server,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(timeout: &str) -> MetadataMap {
+        let mut metadata = MetadataMap::new();
+        metadata.insert("grpc-timeout", timeout.parse().unwrap());
+        metadata
+    }
+
+    #[test]
+    fn parses_every_unit() {
+        let cases = [
+            ("2H", Duration::from_secs(7200)),
+            ("3M", Duration::from_secs(180)),
+            ("30S", Duration::from_secs(30)),
+            ("500m", Duration::from_millis(500)),
+            ("250u", Duration::from_micros(250)),
+            ("100n", Duration::from_nanos(100)),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(client_timeout(&metadata(value)), Some(expected), "{}", value);
+        }
+    }
+
+    #[test]
+    fn ignores_malformed_values() {
+        assert_eq!(client_timeout(&MetadataMap::new()), None);
+        for value in ["S", "5", "5s", "-5S", "1.5S", "abcS", "123456789S"] {
+            assert_eq!(client_timeout(&metadata(value)), None, "{}", value);
+        }
+        // 8 digits is the most the spec allows
+        assert_eq!(client_timeout(&metadata("99999999H")), Some(Duration::from_secs(99_999_999 * 3600)));
+    }
+
+    fn request(timeout: Option<&str>, received_at: Option<Instant>) -> Request<()> {
+        let mut request = Request::new(());
+        if let Some(timeout) = timeout {
+            *request.metadata_mut() = metadata(timeout);
+        }
+        if let Some(received_at) = received_at {
+            request.extensions_mut().insert(ReceivedAt(received_at));
+        }
+        request
+    }
+
+    #[test]
+    fn effective_deadline_takes_the_tighter_limit() {
+        let received = Instant::now();
+        let server = Duration::from_secs(120);
+        let client = effective_deadline(&request(Some("500m"), Some(received)), server);
+        assert_eq!(client, received + Duration::from_millis(500));
+        let server_bound = effective_deadline(&request(Some("5M"), Some(received)), server);
+        assert!(server_bound >= received + server && server_bound < received + Duration::from_secs(180));
+        assert!(effective_deadline(&request(None, None), server) >= received + server);
+    }
+
+    #[test]
+    fn client_deadline_counts_from_arrival() {
+        let received = Instant::now() - Duration::from_millis(300);
+        let stamped = request(Some("500m"), Some(received));
+        assert_eq!(client_deadline(&stamped), Some(received + Duration::from_millis(500)));
+        assert_eq!(client_deadline(&request(None, Some(received))), None);
+        // Unstamped requests count from now
+        let unstamped = client_deadline(&request(Some("500m"), None)).unwrap();
+        assert!(unstamped >= received + Duration::from_millis(800));
+    }
+
+    #[test]
+    fn rpc_deadline_takes_the_earlier_limit() {
+        let received = Instant::now();
+        let limit = Some(Duration::from_secs(1));
+        assert_eq!(rpc_deadline(&request(None, Some(received)), None), None);
+        assert_eq!(rpc_deadline(&request(None, Some(received)), limit), Some(received + Duration::from_secs(1)));
+        let client = request(Some("500m"), Some(received));
+        assert_eq!(rpc_deadline(&client, None), Some(received + Duration::from_millis(500)));
+        assert_eq!(rpc_deadline(&client, limit), Some(received + Duration::from_millis(500)));
+        assert_eq!(rpc_deadline(&request(Some("5S"), Some(received)), limit), Some(received + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn layer_stamps_arrival() {
+        let before = Instant::now();
+        let stamped = ReceivedAtLayer.call(Request::new(())).unwrap();
+        assert!(stamped.extensions().get::<ReceivedAt>().is_some_and(|ReceivedAt(at)| *at >= before));
+    }
+}
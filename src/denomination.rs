@@ -0,0 +1,219 @@
+// The wallet reports raw assets; by default one nock is 2^16 assets.
+// Overridable with ASSETS_PER_NOCK for networks with another denomination.
+pub const ASSETS_PER_NOCK: u64 = 65536;
+
+// Past this many assets an f64 can no longer hold every integer
+pub const MAX_EXACT_ASSETS: u64 = 1 << 53;
+
+// Raw assets to nocks. Exact for any balance up to MAX_EXACT_ASSETS when
+// the divisor is a power of two.
+pub fn assets_to_nocks(assets: u64, assets_per_nock: u64) -> f64 {
+    assets as f64 / assets_per_nock as f64
+}
+
+// An f64 displays at most about 16 significant decimal digits, so rounding
+// to more places than that has no visible effect. It also keeps
+// assets * 10^decimals well inside u128.
+pub const MAX_ROUNDING_DECIMALS: u32 = 16;
+
+// How a balance is rounded to a fixed number of decimal places of nocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rounding {
+    // Plain division, no rounding
+    None,
+    // Towards zero
+    Truncate,
+    // Ties away from zero
+    HalfUp,
+    // Ties to the even neighbour (banker's rounding)
+    HalfEven,
+}
+
+impl Rounding {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "none" => Ok(Rounding::None),
+            "truncate" => Ok(Rounding::Truncate),
+            "half_up" => Ok(Rounding::HalfUp),
+            "half_even" | "bankers" => Ok(Rounding::HalfEven),
+            other => Err(format!("Unknown rounding mode '{}' (expected none, truncate, half_up or half_even)", other)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Rounding::None => "none",
+            Rounding::Truncate => "truncate",
+            Rounding::HalfUp => "half_up",
+            Rounding::HalfEven => "half_even",
+        }
+    }
+}
+
+// Raw assets to nocks rounded to `decimals` places. The rounding is done on
+// integers so no binary float error creeps in before the final division.
+pub fn assets_to_nocks_rounded(assets: u64, assets_per_nock: u64, rounding: Rounding, decimals: u32) -> f64 {
+    if rounding == Rounding::None {
+        return assets_to_nocks(assets, assets_per_nock);
+    }
+
+    let scale = 10u128.pow(decimals.min(MAX_ROUNDING_DECIMALS));
+    let scaled = assets as u128 * scale;
+    let divisor = assets_per_nock as u128;
+    let (quotient, remainder) = (scaled / divisor, scaled % divisor);
+    // Compare twice the remainder so odd divisors find their ties exactly
+    let twice_remainder = remainder * 2;
+
+    let rounded = match rounding {
+        Rounding::None | Rounding::Truncate => quotient,
+        Rounding::HalfUp => quotient + u128::from(twice_remainder >= divisor),
+        Rounding::HalfEven => {
+            let round_up = twice_remainder > divisor || (twice_remainder == divisor && quotient % 2 == 1);
+            quotient + u128::from(round_up)
+        }
+    };
+    rounded as f64 / scale as f64
+}
+
+// Refuse to report a NaN or infinite amount of nocks. Can't happen with a
+// non-zero divisor, but a serialized NaN would be silently wrong.
+pub fn check_finite(nocks: f64) -> Result<f64, String> {
+    if nocks.is_finite() {
+        Ok(nocks)
+    } else {
+        Err(format!("Computed balance is not a finite number: {}", nocks))
+    }
+}
+
+// Whether assets_to_nocks may have rounded this amount. The quotient is an
+// exact f64 only if, in lowest terms, its denominator is a power of two and
+// the numerator fits in 53 bits.
+pub fn is_precision_lossy(assets: u64, assets_per_nock: u64) -> bool {
+    if assets > MAX_EXACT_ASSETS {
+        return true;
+    }
+    let denominator = assets_per_nock / gcd(assets, assets_per_nock);
+    !denominator.is_power_of_two()
+}
+
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+// Nocks to raw assets, rounding to the nearest asset with ties away from zero
+pub fn nocks_to_assets(nocks: f64, assets_per_nock: u64) -> Result<u64, String> {
+    if !nocks.is_finite() || nocks < 0.0 {
+        return Err(format!("Nocks must be a finite, non-negative number, got {}", nocks));
+    }
+
+    let assets = (nocks * assets_per_nock as f64).round();
+    // u64::MAX isn't representable as f64; anything at or above 2^64 overflows
+    if assets >= u64::MAX as f64 {
+        return Err(format!("Nocks value {} exceeds the maximum representable assets", nocks));
+    }
+    Ok(assets as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One asset in nocks at the default divisor; exact since it's a power of two
+    const ASSET: f64 = 1.0 / ASSETS_PER_NOCK as f64;
+
+    #[test]
+    fn nocks_to_assets_rounds_to_nearest_asset() {
+        assert_eq!(nocks_to_assets(0.5, ASSETS_PER_NOCK), Ok(32768));
+        assert_eq!(nocks_to_assets(3.0, ASSETS_PER_NOCK), Ok(196608));
+        assert_eq!(nocks_to_assets(1.25 * ASSET, ASSETS_PER_NOCK), Ok(1));
+        assert_eq!(nocks_to_assets(1.75 * ASSET, ASSETS_PER_NOCK), Ok(2));
+        // Ties go away from zero
+        assert_eq!(nocks_to_assets(1.5 * ASSET, ASSETS_PER_NOCK), Ok(2));
+        assert_eq!(nocks_to_assets(0.5 * ASSET, ASSETS_PER_NOCK), Ok(1));
+        assert_eq!(nocks_to_assets(0.0, ASSETS_PER_NOCK), Ok(0));
+    }
+
+    #[test]
+    fn nocks_to_assets_rejects_invalid_input() {
+        for nocks in [-1.0, -ASSET, f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            assert!(nocks_to_assets(nocks, ASSETS_PER_NOCK).is_err(), "{}", nocks);
+        }
+        assert!(nocks_to_assets(1e30, ASSETS_PER_NOCK).is_err());
+        assert!(nocks_to_assets(u64::MAX as f64 / ASSETS_PER_NOCK as f64, ASSETS_PER_NOCK).is_err());
+    }
+
+    #[test]
+    fn precision_loss_depends_on_the_divisor() {
+        assert!(!is_precision_lossy(98304, ASSETS_PER_NOCK));
+        assert!(!is_precision_lossy(MAX_EXACT_ASSETS, ASSETS_PER_NOCK));
+        assert!(is_precision_lossy(MAX_EXACT_ASSETS + 1, ASSETS_PER_NOCK));
+        // 1/3 has no exact binary form, but 3/3 and 3/6 do
+        assert!(is_precision_lossy(1, 3));
+        assert!(!is_precision_lossy(3, 3));
+        assert!(!is_precision_lossy(3, 6));
+        assert!(is_precision_lossy(1, 10));
+        assert!(!is_precision_lossy(0, 3));
+    }
+
+    #[test]
+    fn rounding_to_whole_nocks() {
+        let round = |assets, rounding| assets_to_nocks_rounded(assets, ASSETS_PER_NOCK, rounding, 0);
+        // Exact ties: 1.5 and 2.5 nocks
+        assert_eq!(round(98304, Rounding::Truncate), 1.0);
+        assert_eq!(round(98304, Rounding::HalfUp), 2.0);
+        assert_eq!(round(98304, Rounding::HalfEven), 2.0);
+        assert_eq!(round(163840, Rounding::Truncate), 2.0);
+        assert_eq!(round(163840, Rounding::HalfUp), 3.0);
+        assert_eq!(round(163840, Rounding::HalfEven), 2.0);
+        // One asset either side of a tie
+        assert_eq!(round(163839, Rounding::HalfUp), 2.0);
+        assert_eq!(round(163839, Rounding::HalfEven), 2.0);
+        assert_eq!(round(163841, Rounding::Truncate), 2.0);
+        assert_eq!(round(163841, Rounding::HalfEven), 3.0);
+    }
+
+    #[test]
+    fn rounding_to_decimal_places() {
+        // 0.25 nocks is a tie at one decimal
+        assert_eq!(assets_to_nocks_rounded(16384, ASSETS_PER_NOCK, Rounding::Truncate, 1), 0.2);
+        assert_eq!(assets_to_nocks_rounded(16384, ASSETS_PER_NOCK, Rounding::HalfUp, 1), 0.3);
+        assert_eq!(assets_to_nocks_rounded(16384, ASSETS_PER_NOCK, Rounding::HalfEven, 1), 0.2);
+        assert_eq!(assets_to_nocks_rounded(1, ASSETS_PER_NOCK, Rounding::HalfUp, 4), 0.0);
+        assert_eq!(assets_to_nocks_rounded(4, ASSETS_PER_NOCK, Rounding::HalfUp, 4), 0.0001);
+    }
+
+    #[test]
+    fn rounding_with_odd_divisor() {
+        let round = |assets, rounding| assets_to_nocks_rounded(assets, 3, rounding, 0);
+        // Thirds never tie, so both half modes agree
+        for (assets, truncated, nearest) in [(1, 0.0, 0.0), (2, 0.0, 1.0), (4, 1.0, 1.0), (5, 1.0, 2.0)] {
+            assert_eq!(round(assets, Rounding::Truncate), truncated, "{} assets", assets);
+            assert_eq!(round(assets, Rounding::HalfUp), nearest, "{} assets", assets);
+            assert_eq!(round(assets, Rounding::HalfEven), nearest, "{} assets", assets);
+        }
+        assert_eq!(assets_to_nocks_rounded(1, 3, Rounding::HalfUp, 2), 0.33);
+        assert_eq!(assets_to_nocks_rounded(2, 3, Rounding::HalfEven, 2), 0.67);
+    }
+
+    #[test]
+    fn rounding_with_even_non_binary_divisor() {
+        // Halves of 6 are exact ties
+        assert_eq!(assets_to_nocks_rounded(3, 6, Rounding::HalfUp, 0), 1.0);
+        assert_eq!(assets_to_nocks_rounded(3, 6, Rounding::HalfEven, 0), 0.0);
+        assert_eq!(assets_to_nocks_rounded(9, 6, Rounding::HalfEven, 0), 2.0);
+        assert_eq!(assets_to_nocks_rounded(4, 6, Rounding::HalfEven, 0), 1.0);
+    }
+
+    #[test]
+    fn check_finite_rejects_nan_and_infinities() {
+        assert_eq!(check_finite(1.5), Ok(1.5));
+        assert_eq!(check_finite(0.0), Ok(0.0));
+        for nocks in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            let error = check_finite(nocks).unwrap_err();
+            assert!(error.contains("not a finite number"), "{}", error);
+        }
+    }
+}
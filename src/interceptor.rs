@@ -0,0 +1,269 @@
+use std::net::IpAddr;
+use tonic::{service::Interceptor, Request, Status};
+
+// A single allowlist entry, e.g. `10.0.0.0/8` or `::1/128`
+#[derive(Debug, Clone, Copy)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    // Parse `addr/len`; a bare address is treated as a single host
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let value = value.trim();
+        let (addr, prefix_len) = match value.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (value, None),
+        };
+
+        let network: IpAddr = addr
+            .parse()
+            .map_err(|e| format!("Invalid CIDR address '{}': {}", value, e))?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse::<u8>()
+                .map_err(|e| format!("Invalid CIDR prefix '{}': {}", value, e))?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Err(format!("Invalid CIDR prefix '{}': must be at most {}", value, max_len));
+        }
+
+        Ok(Cidr { network, prefix_len })
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        // Peers on a dual-stack listener show up as IPv4-mapped IPv6 addresses
+        match (self.network, addr.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                prefix_matches(&network.octets(), &addr.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                prefix_matches(&network.octets(), &addr.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+fn prefix_matches(network: &[u8], addr: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = (prefix_len / 8) as usize;
+    if network[..full_bytes] != addr[..full_bytes] {
+        return false;
+    }
+
+    let remaining_bits = prefix_len % 8;
+    if remaining_bits == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - remaining_bits);
+    network[full_bytes] & mask == addr[full_bytes] & mask
+}
+
+// Parse a comma-separated list of CIDRs, as read from ALLOWED_CIDRS
+pub fn parse_cidr_list(value: &str) -> Result<Vec<Cidr>, String> {
+    value
+        .split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(Cidr::parse)
+        .collect()
+}
+
+// Compare without short-circuiting so timing doesn't leak how much of the token matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Runs in front of every RPC; an empty allowlist allows all peers and
+// a missing token disables authentication
+#[derive(Debug, Clone, Default)]
+pub struct AccessInterceptor {
+    allowed_cidrs: Vec<Cidr>,
+    api_token: Option<String>,
+}
+
+impl AccessInterceptor {
+    pub fn new(allowed_cidrs: Vec<Cidr>, api_token: Option<String>) -> Self {
+        AccessInterceptor { allowed_cidrs, api_token }
+    }
+
+    fn token_valid(&self, request: &Request<()>) -> bool {
+        let Some(expected) = &self.api_token else {
+            return true;
+        };
+
+        // Accept both `Bearer <token>` and the bare token
+        let provided = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.strip_prefix("Bearer ").unwrap_or(value));
+
+        match provided {
+            Some(token) => {
+                let valid = constant_time_eq(token.as_bytes(), expected.as_bytes());
+                if !valid {
+                    log::warn!("Rejected request with invalid API token");
+                }
+                valid
+            }
+            None => {
+                log::warn!("Rejected request without API token");
+                false
+            }
+        }
+    }
+
+    fn peer_allowed(&self, request: &Request<()>) -> bool {
+        if self.allowed_cidrs.is_empty() {
+            return true;
+        }
+
+        match request.remote_addr() {
+            Some(peer) if self.allowed_cidrs.iter().any(|cidr| cidr.contains(peer.ip())) => true,
+            Some(peer) => {
+                log::warn!("Rejected request from disallowed peer: {}", peer);
+                false
+            }
+            None => {
+                log::warn!("Rejected request with unknown peer address");
+                false
+            }
+        }
+    }
+}
+
+impl Interceptor for AccessInterceptor {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        if !self.peer_allowed(&request) {
+            return Err(Status::permission_denied("Peer address not allowed"));
+        }
+        if !self.token_valid(&request) {
+            return Err(Status::unauthenticated("Missing or invalid API token"));
+        }
+        Ok(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use tonic::transport::server::TcpConnectInfo;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    fn request_from(peer: &str) -> Request<()> {
+        let mut request = Request::new(());
+        let remote_addr: SocketAddr = peer.parse().unwrap();
+        request.extensions_mut().insert(TcpConnectInfo { local_addr: None, remote_addr: Some(remote_addr) });
+        request
+    }
+
+    #[test]
+    fn zero_prefix_matches_everything_in_its_family() {
+        let cidr = Cidr::parse("0.0.0.0/0").unwrap();
+        assert!(cidr.contains(ip("10.1.2.3")));
+        assert!(cidr.contains(ip("255.255.255.255")));
+        assert!(!cidr.contains(ip("::1")));
+    }
+
+    #[test]
+    fn full_prefix_matches_a_single_host() {
+        let cidr = Cidr::parse("192.168.1.7/32").unwrap();
+        assert!(cidr.contains(ip("192.168.1.7")));
+        assert!(!cidr.contains(ip("192.168.1.8")));
+        // A bare address is the same as /32
+        assert_eq!(Cidr::parse("192.168.1.7").unwrap().to_string(), "192.168.1.7/32");
+    }
+
+    #[test]
+    fn non_byte_aligned_prefix_masks_partial_octet() {
+        let cidr = Cidr::parse("172.16.0.0/12").unwrap();
+        assert!(cidr.contains(ip("172.16.0.1")));
+        assert!(cidr.contains(ip("172.31.255.255")));
+        assert!(!cidr.contains(ip("172.32.0.0")));
+        assert!(!cidr.contains(ip("172.15.255.255")));
+    }
+
+    #[test]
+    fn ipv6_prefixes() {
+        let cidr = Cidr::parse("fd00::/8").unwrap();
+        assert!(cidr.contains(ip("fd12:3456::1")));
+        assert!(!cidr.contains(ip("fe80::1")));
+        assert!(!cidr.contains(ip("10.0.0.1")));
+        assert!(Cidr::parse("::1/128").unwrap().contains(ip("::1")));
+    }
+
+    #[test]
+    fn ipv4_mapped_peer_matches_ipv4_cidr() {
+        let cidr = Cidr::parse("10.0.0.0/8").unwrap();
+        assert!(cidr.contains(ip("::ffff:10.1.2.3")));
+        assert!(!cidr.contains(ip("::ffff:11.1.2.3")));
+    }
+
+    #[test]
+    fn rejects_bad_cidrs() {
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+        assert!(Cidr::parse("::/129").is_err());
+        assert!(Cidr::parse("abc").is_err());
+        assert!(Cidr::parse("10.0.0.0/abc").is_err());
+        assert!(parse_cidr_list("10.0.0.0/8, nope").is_err());
+        assert_eq!(parse_cidr_list("10.0.0.0/8, ,::1").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn interceptor_checks_peer_against_allowlist() {
+        let mut interceptor = AccessInterceptor::new(parse_cidr_list("10.0.0.0/8").unwrap(), None);
+        assert!(interceptor.call(request_from("10.1.2.3:5000")).is_ok());
+
+        let status = interceptor.call(request_from("192.168.0.1:5000")).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+        // No known peer address can't be matched against the allowlist
+        let status = interceptor.call(Request::new(())).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+        let mut open = AccessInterceptor::new(Vec::new(), None);
+        assert!(open.call(request_from("192.168.0.1:5000")).is_ok());
+    }
+
+    fn request_with_token(value: &str) -> Request<()> {
+        let mut request = Request::new(());
+        request.metadata_mut().insert("authorization", value.parse().unwrap());
+        request
+    }
+
+    #[test]
+    fn constant_time_eq_compares_whole_value() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"", b"secret"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn interceptor_checks_api_token() {
+        let mut interceptor = AccessInterceptor::new(Vec::new(), Some("secret".to_string()));
+        assert!(interceptor.call(request_with_token("Bearer secret")).is_ok());
+        assert!(interceptor.call(request_with_token("secret")).is_ok());
+
+        for request in [request_with_token("Bearer wrong"), request_with_token("wrong"), Request::new(())] {
+            assert_eq!(interceptor.call(request).unwrap_err().code(), tonic::Code::Unauthenticated);
+        }
+    }
+}
@@ -1,147 +1,281 @@
-use tonic::{transport::Server, Request, Response, Status};
-use nockchain::nockchain_service_server::{NockchainService, NockchainServiceServer};
-use nockchain::{GetBalanceRequest, GetBalanceResponse};
-use tokio::process::Command as TokioCommand;
-use tokio::time::{timeout, Duration};
-use env_logger;
-use log;
-use regex::Regex;
+use tonic::transport::Server;
+use nockchain::nockchain_service_server::NockchainServiceServer;
 use dotenvy::dotenv;
+use log::LevelFilter;
 use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::sync::Arc;
+use config::Config;
+use deadline::ReceivedAtLayer;
+use interceptor::AccessInterceptor;
+use service::NockchainServiceImpl;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::oneshot;
+use tokio::time::{sleep, Duration};
+use tokio_stream::wrappers::UnixListenerStream;
+use wallet::WalletProcesses;
+
+mod config;
+mod deadline;
+mod denomination;
+mod interceptor;
+mod sampler;
+mod service;
+mod uds;
+mod wallet;
 
 pub mod nockchain {
     tonic::include_proto!("nockchain");
+
+    // The compiled FileDescriptorSet for protos/nockchain.proto
+    pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("nockchain_descriptor");
 }
 
-// Function to parse nockchain-wallet output and sum all assets
-fn parse_nockchain_output(output: &str) -> Result<u64, String> {
-    if output.trim().is_empty() {
-        log::error!("Empty command output");
-        return Err("Empty command output".to_string());
-    }
+// Logs only when LOG_SAMPLE_RATE picks a request; shown by default so that
+// setting the rate is enough to see samples
+const SAMPLER_TARGET: &str = "nockchain_rpc::sampler";
 
-    log::debug!("Raw output length: {} bytes", output.len());
-    let re = Regex::new(r"^- assets: (\d+)\s*$").map_err(|e| format!("Regex error: {}", e))?;
-    let mut total_assets = 0;
-    let mut asset_count = 0;
+// Dependencies that log every connection and frame at debug level
+const NOISY_TARGETS: &[&str] = &["h2", "hyper", "hyper_util", "tower", "tonic"];
 
-    for line in output.lines() {
-        let line = line.trim();
-        log::debug!("Processing line: {}", line);
+// The level RUST_LOG gives targets it doesn't name; the last bare level wins,
+// as in env_logger. Error when it sets none.
+fn global_level(filters: &str) -> LevelFilter {
+    let directives = filters.split('/').next().unwrap_or_default();
+    directives
+        .rsplit(',')
+        .map(str::trim)
+        .filter(|directive| !directive.contains('='))
+        .find_map(|directive| directive.parse::<LevelFilter>().ok())
+        .unwrap_or(LevelFilter::Error)
+}
 
-        // Skip empty lines and log messages
-        if line.is_empty() || line.contains("\u{001b}") {
-            log::debug!("Skipped line: {}", line);
-            continue;
+// Errors only by default. When RUST_LOG raises the global level past warn the
+// noisy dependencies stay capped at warn; directives such as `h2=debug` or
+// `nockchain_rpc::wallet=trace` override that.
+fn build_logger(rust_log: Option<&str>, target: env_logger::Target) -> env_logger::Logger {
+    let mut builder = env_logger::Builder::new();
+    builder.target(target).filter_level(LevelFilter::Error);
+    builder.filter_module(SAMPLER_TARGET, LevelFilter::Info);
+    if rust_log.map(global_level).is_some_and(|level| level > LevelFilter::Warn) {
+        for target in NOISY_TARGETS {
+            builder.filter_module(target, LevelFilter::Warn);
         }
+    }
+    if let Some(filters) = rust_log {
+        builder.parse_filters(filters);
+    }
+    builder.build()
+}
 
-        if let Some(captures) = re.captures(line.to_lowercase().as_str()) {
-            if let Some(asset_str) = captures.get(1) {
-                let assets: u64 = asset_str.as_str().parse().map_err(|e| format!("Failed to parse assets: {}", e))?;
-                log::info!("Found assets: {}", assets);
-                total_assets += assets;
-                asset_count += 1;
-            }
-        }
+fn init_logger() {
+    let logger = build_logger(env::var("RUST_LOG").ok().as_deref(), env_logger::Target::Stderr);
+    log::set_max_level(logger.filter());
+    log::set_boxed_logger(Box::new(logger)).expect("logger is only initialized once");
+}
+
+// Write the descriptor set to `path`, or raw to stdout, for other-language
+// codegen: `nockchain-rpc print-descriptor nockchain.pb`
+fn print_descriptor(path: Option<String>) -> io::Result<()> {
+    match path {
+        Some(path) => fs::write(path, nockchain::FILE_DESCRIPTOR_SET),
+        None => io::stdout().write_all(nockchain::FILE_DESCRIPTOR_SET),
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = env::args().skip(1);
+    if args.next().as_deref() == Some("print-descriptor") {
+        return Ok(print_descriptor(args.next())?);
     }
 
-    log::info!("Total assets summed: {}, Number of assets found: {}", total_assets, asset_count);
-    Ok(total_assets)
+    dotenv().ok(); // Load .env file, ignore if missing
+    init_logger();
+
+    let config = Config::load().map_err(|e| {
+        log::error!("Invalid configuration: {}", e);
+        e
+    })?;
+
+    runtime_builder(&config).build()?.block_on(serve(config))
 }
 
-#[derive(Debug)]
-struct NockchainServiceImpl;
-
-#[tonic::async_trait]
-impl NockchainService for NockchainServiceImpl {
-    async fn get_balance(
-        &self,
-        request: Request<GetBalanceRequest>,
-    ) -> Result<Response<GetBalanceResponse>, Status> {
-        let pubkey = request.into_inner().pubkey;
-        log::info!("Received GetBalance request for pubkey: {}", pubkey);
-
-        let socket_path = env::var("NOCKCHAIN_SOCKET").map_err(|e| {
-            log::error!("Missing NOCKCHAIN_SOCKET environment variable: {}", e);
-            Status::internal(format!("Missing NOCKCHAIN_SOCKET environment variable: {}", e))
-        })?;
-
-        let timeout_secs = match env::var("COMMAND_TIMEOUT_SECS") {
-            Ok(secs) => secs.parse::<u64>().map_err(|e| {
-                log::error!("Invalid COMMAND_TIMEOUT_SECS: {}", e);
-                Status::invalid_argument(format!("Invalid COMMAND_TIMEOUT_SECS: {}", e))
-            })?,
-            Err(_) => {
-                log::warn!("Missing COMMAND_TIMEOUT_SECS, using default: 120 seconds");
-                120
-            }
-        };
+// The runtime, sized by WORKER_THREADS and MAX_BLOCKING_THREADS when set
+fn runtime_builder(config: &Config) -> tokio::runtime::Builder {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.enable_all();
+    if let Some(worker_threads) = config.worker_threads {
+        builder.worker_threads(worker_threads);
+    }
+    if let Some(max_blocking_threads) = config.max_blocking_threads {
+        builder.max_blocking_threads(max_blocking_threads);
+    }
+    builder
+}
 
-        let output = timeout(Duration::from_secs(timeout_secs), TokioCommand::new("nockchain-wallet")
-            .env("RUST_LOG", "error")
-            .arg("--nockchain-socket")
-            .arg(&socket_path)
-            .arg("list-notes-by-pubkey")
-            .arg(&pubkey)
-            .output())
-            .await
-            .map_err(|_| Status::deadline_exceeded("Command timed out"))?;
-
-        match output {
-            Ok(output) => {
-                log::info!("Command executed, status: {}", output.status);
-                if !output.status.success() {
-                    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-                    log::error!("Command failed: stderr={}", stderr);
-                    return Err(Status::internal(format!("Command execution failed: {}", stderr)));
-                }
-
-                let stdout = String::from_utf8_lossy(&output.stdout).to_string();
-                log::debug!("Raw command output: {}", stdout);
-                match parse_nockchain_output(&stdout) {
-                    Ok(total_assets) => {
-                        log::info!("Total assets: {}", total_assets);
-                        let balance = (total_assets as f64) / 65536.0;
-                        log::info!("Total assets in nocks: {}", balance);
-                        Ok(Response::new(GetBalanceResponse { balance }))
-                    }
-                    Err(error) => {
-                        log::error!("Parsing error: {}", error);
-                        Err(Status::internal(format!("Parsing error: {}", error)))
-                    }
-                }
-            }
-            Err(error) => {
-                log::error!("Command error: {}", error);
-                Err(Status::internal(format!("Server error: {}", error)))
+// Resolves on Ctrl-C or SIGTERM so the server can stop accepting and drain
+async fn shutdown_signal() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => Some(terminate),
+        Err(error) => {
+            log::error!("Failed to listen for SIGTERM: {}", error);
+            None
+        }
+    };
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => {
+            if let Err(error) = result {
+                log::error!("Failed to listen for Ctrl-C: {}", error);
+                std::future::pending::<()>().await;
             }
         }
+        Some(_) = async { terminate.as_mut()?.recv().await } => {}
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    dotenv().ok(); // Load .env file, ignore if missing
-    env_logger::init();
-    
-    let port = match env::var("PORT") {
-        Ok(port) => port.parse::<u16>().map_err(|e| {
-            log::error!("Invalid PORT: {}", e);
-            format!("Invalid PORT: {}", e)
-        })?,
-        Err(_) => {
-            log::warn!("Missing PORT, using default: 3000");
-            3000
-        }
+// Once draining starts, give in-flight requests `grace` to finish, then kill
+// any wallets still running so none outlive the server
+async fn terminate_after_grace(draining: oneshot::Receiver<()>, processes: Arc<WalletProcesses>, grace: Duration) {
+    if draining.await.is_err() {
+        return;
+    }
+    log::info!("Shutting down, draining requests for up to {:?}", grace);
+    sleep(grace).await;
+    let terminated = processes.terminate_all();
+    if terminated > 0 {
+        log::warn!("Terminated {} wallet processes still running after the grace period", terminated);
+    }
+}
+
+async fn serve(config: Config) -> Result<(), Box<dyn std::error::Error>> {
+    if config.nockchain_socket.is_none() {
+        log::warn!("NOCKCHAIN_SOCKET is not set, GetBalance requests will fail");
+    }
+    match wallet::find_wallet_binary() {
+        Some(path) => log::info!("Using wallet binary: {}", path.display()),
+        None => log::warn!(
+            "{} not found on PATH, GetBalance will return UNIMPLEMENTED until it is installed",
+            wallet::WALLET_BIN
+        ),
+    }
+
+    let addr = config.listen_addr();
+    let interceptor = AccessInterceptor::new(config.allowed_cidrs.clone(), config.api_token.clone());
+    log::info!("Starting gRPC server: {}", config.summary());
+    let config = Arc::new(config);
+    let processes = Arc::new(WalletProcesses::default());
+    let (draining_sender, draining) = oneshot::channel();
+    tokio::spawn(terminate_after_grace(draining, processes.clone(), config.shutdown_grace));
+    let shutdown = async move {
+        shutdown_signal().await;
+        let _ = draining_sender.send(());
     };
 
-    let addr = format!("127.0.0.1:{}", port).parse()?;
-    log::info!("Starting gRPC server on http://{}", addr);
-    
-    Server::builder()
-        .add_service(NockchainServiceServer::new(NockchainServiceImpl))
-        .serve(addr)
-        .await?;
-    
+    let router = Server::builder()
+        .layer(tonic::service::interceptor(ReceivedAtLayer))
+        .http2_keepalive_interval(config.http2_keepalive_interval)
+        .http2_keepalive_timeout(Some(config.http2_keepalive_timeout))
+        .tcp_keepalive(config.tcp_keepalive)
+        .add_service(NockchainServiceServer::with_interceptor(NockchainServiceImpl::new(config.clone(), processes), interceptor));
+
+    match &config.uds_path {
+        Some(path) => {
+            let incoming = UnixListenerStream::new(uds::bind(path)?);
+            let result = router.serve_with_incoming_shutdown(incoming, shutdown).await;
+            uds::cleanup(path);
+            result?;
+        }
+        None => router.serve_with_shutdown(addr, shutdown).await?,
+    }
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::{Level, Log, Record};
+    use std::sync::Mutex;
+
+    // Collects everything the logger writes
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Which of `probes` (target, level) the logger would write for this RUST_LOG
+    fn emitted(rust_log: Option<&str>, probes: &[(&str, Level)]) -> Vec<bool> {
+        let capture = Capture::default();
+        let logger = build_logger(rust_log, env_logger::Target::Pipe(Box::new(capture.clone())));
+        probes
+            .iter()
+            .map(|(target, level)| {
+                let before = capture.0.lock().unwrap().len();
+                logger.log(&Record::builder().args(format_args!("probe")).target(target).level(*level).build());
+                capture.0.lock().unwrap().len() > before
+            })
+            .collect()
+    }
+
+    const PROBES: &[(&str, Level)] = &[
+        ("nockchain_rpc", Level::Error),
+        ("nockchain_rpc", Level::Info),
+        ("h2", Level::Error),
+        ("h2", Level::Warn),
+        ("h2", Level::Debug),
+    ];
+
+    #[test]
+    fn default_is_errors_only_for_every_target() {
+        assert_eq!(emitted(None, PROBES), [true, false, true, false, false]);
+        assert_eq!(emitted(Some("error"), PROBES), [true, false, true, false, false]);
+    }
+
+    #[test]
+    fn raising_the_global_level_caps_noisy_targets_at_warn() {
+        assert_eq!(emitted(Some("debug"), PROBES), [true, true, true, true, false]);
+        assert_eq!(emitted(Some("h2=debug,info"), PROBES), [true, true, true, true, true]);
+    }
+
+    #[test]
+    fn named_targets_leave_the_global_level_alone() {
+        assert_eq!(emitted(Some("nockchain_rpc=info"), PROBES), [true, true, true, false, false]);
+    }
+
+    #[test]
+    fn sampler_logs_at_info_unless_overridden() {
+        let probes = [(SAMPLER_TARGET, Level::Info), ("nockchain_rpc::service", Level::Info)];
+        assert_eq!(emitted(None, &probes), [true, false]);
+        assert_eq!(emitted(Some("nockchain_rpc::sampler=off"), &probes), [false, false]);
+    }
+
+    #[test]
+    fn global_level_reads_bare_levels() {
+        assert_eq!(global_level("info"), LevelFilter::Info);
+        assert_eq!(global_level("h2=trace,warn,debug"), LevelFilter::Debug);
+        assert_eq!(global_level("nockchain_rpc=info"), LevelFilter::Error);
+        assert_eq!(global_level("trace/foo"), LevelFilter::Trace);
+    }
+
+    #[test]
+    fn runtime_builder_uses_configured_threads() {
+        let env = |name: &str| match name {
+            "WORKER_THREADS" => Some("4".to_string()),
+            "MAX_BLOCKING_THREADS" => Some("8".to_string()),
+            _ => None,
+        };
+        let config = Config::from_sources(Vec::new(), &env).unwrap();
+        let runtime = runtime_builder(&config).build().unwrap();
+        assert_eq!(runtime.metrics().num_workers(), 4);
+    }
+}
@@ -0,0 +1,54 @@
+use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use tonic::{Request, Response, Status};
+
+// Logs full detail for 1 in `rate` requests. Only the decoded message is
+// logged, never metadata, so tokens in `authorization` stay out of the logs.
+#[derive(Debug)]
+pub struct LogSampler {
+    rate: Option<u64>,
+    counter: AtomicU64,
+}
+
+// A request picked for detailed logging, finished once the response is ready
+pub struct Sample {
+    id: u64,
+    peer: Option<SocketAddr>,
+    started: Instant,
+}
+
+impl LogSampler {
+    pub fn new(rate: Option<u64>) -> Self {
+        LogSampler { rate, counter: AtomicU64::new(0) }
+    }
+
+    pub fn begin<T: Debug>(&self, method: &str, request: &Request<T>) -> Option<Sample> {
+        let rate = self.rate?;
+        let id = self.counter.fetch_add(1, Ordering::Relaxed);
+        if !id.is_multiple_of(rate) {
+            return None;
+        }
+
+        let peer = request.remote_addr();
+        log::info!("[sample {}] {} from {:?}: {:?}", id, method, peer, request.get_ref());
+        Some(Sample { id, peer, started: Instant::now() })
+    }
+}
+
+impl Sample {
+    pub fn finish<T: Debug>(self, method: &str, result: &Result<Response<T>, Status>) {
+        let elapsed = self.started.elapsed();
+        match result {
+            Ok(response) => log::info!(
+                "[sample {}] {} from {:?} ok in {:?}: {:?}",
+                self.id, method, self.peer, elapsed, response.get_ref()
+            ),
+            Err(status) => log::info!(
+                "[sample {}] {} from {:?} failed in {:?}: {:?} {}",
+                self.id, method, self.peer, elapsed, status.code(), status.message()
+            ),
+        }
+    }
+}
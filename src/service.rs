@@ -0,0 +1,416 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::time::{sleep, timeout, Instant};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+
+use crate::config::Config;
+use crate::nockchain::nockchain_service_server::NockchainService;
+use crate::nockchain::{
+    convert_request, ConvertRequest, ConvertResponse, GetBalanceRequest, GetBalanceResponse,
+    GetMetricsRequest, GetMetricsResponse, GetServerInfoRequest, GetServerInfoResponse, Note,
+    SubscribeBalanceRequest,
+};
+use crate::denomination::Rounding;
+use crate::sampler::LogSampler;
+use crate::wallet::{self, WalletBalance, WalletError, WalletMetrics, WalletProcesses};
+use crate::{deadline, denomination};
+
+// Reported by GetServerInfo so clients can detect new fields and RPCs
+const PROTO_SCHEMA_VERSION: u32 = 10;
+
+// Every RPC name, as accepted by ENABLED_METHODS
+pub const METHODS: &[&str] = &["GetBalance", "GetServerInfo", "Convert", "GetMetrics", "SubscribeBalance"];
+
+// Most of the wallet's stderr that DEBUG_ERRORS puts in a status message
+const DEBUG_STDERR_LIMIT: usize = 512;
+
+// Responses a SubscribeBalance stream can buffer before the poller waits on the client
+const SUBSCRIPTION_BUFFER: usize = 4;
+
+#[derive(Debug)]
+pub struct NockchainServiceImpl {
+    config: Arc<Config>,
+    sampler: LogSampler,
+    // Shared with SubscribeBalance pollers
+    wallet_metrics: Arc<WalletMetrics>,
+    // Shared with main, which terminates them on shutdown
+    wallet_processes: Arc<WalletProcesses>,
+    // Open SubscribeBalance streams, capped at MAX_BALANCE_STREAMS
+    balance_streams: Arc<AtomicUsize>,
+}
+
+impl NockchainServiceImpl {
+    pub fn new(config: Arc<Config>, wallet_processes: Arc<WalletProcesses>) -> Self {
+        let sampler = LogSampler::new(config.log_sample_rate);
+        NockchainServiceImpl {
+            config,
+            sampler,
+            wallet_metrics: Arc::default(),
+            wallet_processes,
+            balance_streams: Arc::default(),
+        }
+    }
+}
+
+// Holds one of the MAX_BALANCE_STREAMS slots until its poller exits
+#[derive(Debug)]
+struct StreamSlot(Arc<AtomicUsize>);
+
+impl StreamSlot {
+    fn acquire(streams: &Arc<AtomicUsize>, max: usize) -> Option<StreamSlot> {
+        streams
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |open| (open < max).then_some(open + 1))
+            .ok()?;
+        Some(StreamSlot(streams.clone()))
+    }
+}
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl NockchainServiceImpl {
+    // The status to return if ENABLED_METHODS excludes this method
+    fn disabled(&self, method: &str) -> Option<Status> {
+        if self.config.method_enabled(method) {
+            return None;
+        }
+        log::debug!("Rejected call to disabled method {}", method);
+        Some(Status::unimplemented(format!("{} is disabled on this server", method)))
+    }
+
+    // Bound a whole handler by REQUEST_TIMEOUT_SECS. The wallet's own
+    // COMMAND_TIMEOUT_SECS still applies inside it.
+    async fn with_request_timeout<T>(
+        &self,
+        method: &str,
+        handler: impl Future<Output = Result<T, Status>>,
+    ) -> Result<T, Status> {
+        let Some(limit) = self.config.request_timeout else {
+            return handler.await;
+        };
+        match timeout(limit, handler).await {
+            Ok(result) => result,
+            Err(_) => {
+                log::warn!("{} exceeded the request timeout of {:?}", method, limit);
+                Err(Status::deadline_exceeded(format!("{} exceeded the request timeout of {:?}", method, limit)))
+            }
+        }
+    }
+}
+
+impl From<WalletError> for Status {
+    fn from(error: WalletError) -> Self {
+        match error {
+            WalletError::NotFound => Status::unimplemented(error.to_string()),
+            WalletError::Timeout => Status::deadline_exceeded(error.to_string()),
+            WalletError::Terminated => Status::unavailable(error.to_string()),
+            error => Status::internal(error.to_string()),
+        }
+    }
+}
+
+// The status for a wallet error. With DEBUG_ERRORS a failed wallet's stderr
+// is appended, truncated to DEBUG_STDERR_LIMIT bytes.
+fn wallet_status(error: WalletError, debug_errors: bool) -> Status {
+    match &error {
+        WalletError::Failed { stderr, .. } if debug_errors => {
+            let stderr = stderr.trim();
+            let mut end = stderr.len().min(DEBUG_STDERR_LIMIT);
+            while !stderr.is_char_boundary(end) {
+                end -= 1;
+            }
+            let ellipsis = if end < stderr.len() { "..." } else { "" };
+            Status::internal(format!("{}: {}{}", error, &stderr[..end], ellipsis))
+        }
+        _ => error.into(),
+    }
+}
+
+impl NockchainServiceImpl {
+    async fn handle_get_balance(
+        &self,
+        request: Request<GetBalanceRequest>,
+    ) -> Result<Response<GetBalanceResponse>, Status> {
+        let wallet_deadline = deadline::effective_deadline(&request, self.config.command_timeout);
+        let rpc_deadline = deadline::rpc_deadline(&request, self.config.request_timeout);
+        let GetBalanceRequest { pubkey, at_height, include_notes } = request.into_inner();
+        log::info!("Received GetBalance request for pubkey: {}", pubkey);
+
+        if let Some(height) = at_height {
+            log::warn!("Rejected historical balance request at height {}", height);
+            return Err(Status::unimplemented(
+                "Historical balances are not supported: nockchain-wallet has no height argument",
+            ));
+        }
+
+        let pending = self.wallet_metrics.begin(rpc_deadline);
+        let result = wallet::query_balance(&self.config, &self.wallet_processes, &pubkey, wallet_deadline).await;
+        pending.finish(&result);
+        let wallet_balance = result.map_err(|error| wallet_status(error, self.config.debug_errors))?;
+        let response = balance_response(&self.config, wallet_balance, include_notes).map_err(Status::internal)?;
+        Ok(Response::new(response))
+    }
+
+    async fn handle_subscribe_balance(
+        &self,
+        request: Request<SubscribeBalanceRequest>,
+    ) -> Result<Response<ReceiverStream<Result<GetBalanceResponse, Status>>>, Status> {
+        let SubscribeBalanceRequest { pubkey, include_notes } = request.into_inner();
+        log::info!("Received SubscribeBalance request for pubkey: {}", pubkey);
+
+        let Some(slot) = StreamSlot::acquire(&self.balance_streams, self.config.max_balance_streams) else {
+            log::warn!("Rejected SubscribeBalance: {} streams already open", self.config.max_balance_streams);
+            return Err(Status::resource_exhausted(format!(
+                "Too many balance streams: at most {} may be open",
+                self.config.max_balance_streams
+            )));
+        };
+        let (sender, receiver) = mpsc::channel(SUBSCRIPTION_BUFFER);
+        tokio::spawn(poll_balance(
+            self.config.clone(),
+            self.wallet_metrics.clone(),
+            self.wallet_processes.clone(),
+            pubkey,
+            include_notes,
+            sender,
+            slot,
+        ));
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    async fn handle_get_server_info(
+        &self,
+        _request: Request<GetServerInfoRequest>,
+    ) -> Result<Response<GetServerInfoResponse>, Status> {
+        Ok(Response::new(GetServerInfoResponse {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            schema_version: PROTO_SCHEMA_VERSION,
+            features: self.config.features(),
+            enabled_methods: METHODS
+                .iter()
+                .filter(|method| self.config.method_enabled(method))
+                .map(|method| method.to_string())
+                .collect(),
+        }))
+    }
+
+    async fn handle_convert(
+        &self,
+        request: Request<ConvertRequest>,
+    ) -> Result<Response<ConvertResponse>, Status> {
+        let assets_per_nock = self.config.assets_per_nock;
+        let response = match request.into_inner().value {
+            Some(convert_request::Value::Assets(assets)) => ConvertResponse {
+                assets,
+                nocks: denomination::check_finite(denomination::assets_to_nocks(assets, assets_per_nock))
+                    .map_err(Status::internal)?,
+            },
+            Some(convert_request::Value::Nocks(nocks)) => {
+                let assets = denomination::nocks_to_assets(nocks, assets_per_nock).map_err(Status::invalid_argument)?;
+                ConvertResponse {
+                    assets,
+                    nocks: denomination::check_finite(denomination::assets_to_nocks(assets, assets_per_nock))
+                        .map_err(Status::internal)?,
+                }
+            }
+            None => return Err(Status::invalid_argument("Either assets or nocks must be set")),
+        };
+        Ok(Response::new(response))
+    }
+
+    async fn handle_get_metrics(
+        &self,
+        _request: Request<GetMetricsRequest>,
+    ) -> Result<Response<GetMetricsResponse>, Status> {
+        let wallet_outcomes = self
+            .wallet_metrics
+            .snapshot()
+            .into_iter()
+            .map(|(outcome, count)| (outcome.to_string(), count))
+            .collect();
+        Ok(Response::new(GetMetricsResponse { wallet_outcomes }))
+    }
+}
+
+// Build the GetBalance reply from a wallet listing
+fn balance_response(
+    config: &Config,
+    wallet_balance: WalletBalance,
+    include_notes: bool,
+) -> Result<GetBalanceResponse, String> {
+    let total_assets = wallet_balance.total_assets;
+    log::info!("Total assets: {}", total_assets);
+    let rounding = config.balance_rounding;
+    let balance = denomination::check_finite(denomination::assets_to_nocks_rounded(
+        total_assets,
+        config.assets_per_nock,
+        rounding,
+        config.balance_decimals,
+    ))?;
+    log::info!("Total assets in nocks: {}", balance);
+    let unrepresentable = denomination::is_precision_lossy(total_assets, config.assets_per_nock);
+    if unrepresentable {
+        log::warn!("Balance of {} assets has no exact f64 value in nocks, clients should use raw_assets", total_assets);
+    }
+    // Rounding on purpose is lossy too, but not worth a warning
+    let precision_lossy =
+        unrepresentable || balance != denomination::assets_to_nocks(total_assets, config.assets_per_nock);
+    Ok(GetBalanceResponse {
+        balance,
+        raw_assets: total_assets,
+        precision_lossy,
+        rounding: rounding.name().to_string(),
+        rounding_decimals: if rounding == Rounding::None { 0 } else { config.balance_decimals },
+        notes: if include_notes {
+            wallet_balance
+                .notes
+                .into_iter()
+                .map(|note| Note { name: note.name, assets: note.assets })
+                .collect()
+        } else {
+            Vec::new()
+        },
+    })
+}
+
+// Query the wallet every BALANCE_POLL_SECS for one SubscribeBalance stream,
+// sending a response only when it differs from the last one sent. Stops on
+// the first wallet error, when the client goes away, or at shutdown. The
+// stream's slot is freed when this returns.
+async fn poll_balance(
+    config: Arc<Config>,
+    wallet_metrics: Arc<WalletMetrics>,
+    wallet_processes: Arc<WalletProcesses>,
+    pubkey: String,
+    include_notes: bool,
+    sender: mpsc::Sender<Result<GetBalanceResponse, Status>>,
+    _slot: StreamSlot,
+) {
+    let mut last_sent = None;
+    loop {
+        let result = tokio::select! {
+            result = wallet::query_balance(&config, &wallet_processes, &pubkey, Instant::now() + config.command_timeout) => result,
+            _ = sender.closed() => break,
+        };
+        wallet_metrics.record(&result);
+        match result {
+            Ok(wallet_balance) => {
+                let response = match balance_response(&config, wallet_balance, include_notes) {
+                    Ok(response) => response,
+                    Err(error) => {
+                        let _ = sender.send(Err(Status::internal(error))).await;
+                        break;
+                    }
+                };
+                if last_sent.as_ref() != Some(&response) {
+                    if sender.send(Ok(response.clone())).await.is_err() {
+                        break;
+                    }
+                    last_sent = Some(response);
+                }
+            }
+            Err(error) => {
+                let _ = sender.send(Err(wallet_status(error, config.debug_errors))).await;
+                break;
+            }
+        }
+
+        tokio::select! {
+            _ = sleep(config.balance_poll_interval) => {}
+            _ = sender.closed() => break,
+            _ = wallet_processes.terminated() => {
+                let _ = sender.send(Err(WalletError::Terminated.into())).await;
+                break;
+            }
+        }
+    }
+    log::info!("SubscribeBalance for pubkey {} ended", pubkey);
+}
+
+// Each RPC is checked against ENABLED_METHODS, goes through the log
+// sampler and is bounded by the request timeout
+#[tonic::async_trait]
+impl NockchainService for NockchainServiceImpl {
+    type SubscribeBalanceStream = ReceiverStream<Result<GetBalanceResponse, Status>>;
+
+    async fn get_balance(
+        &self,
+        request: Request<GetBalanceRequest>,
+    ) -> Result<Response<GetBalanceResponse>, Status> {
+        if let Some(status) = self.disabled("GetBalance") {
+            return Err(status);
+        }
+        let sample = self.sampler.begin("GetBalance", &request);
+        let result = self.with_request_timeout("GetBalance", self.handle_get_balance(request)).await;
+        if let Some(sample) = sample {
+            sample.finish("GetBalance", &result);
+        }
+        result
+    }
+
+    async fn get_server_info(
+        &self,
+        request: Request<GetServerInfoRequest>,
+    ) -> Result<Response<GetServerInfoResponse>, Status> {
+        if let Some(status) = self.disabled("GetServerInfo") {
+            return Err(status);
+        }
+        let sample = self.sampler.begin("GetServerInfo", &request);
+        let result = self.with_request_timeout("GetServerInfo", self.handle_get_server_info(request)).await;
+        if let Some(sample) = sample {
+            sample.finish("GetServerInfo", &result);
+        }
+        result
+    }
+
+    async fn convert(
+        &self,
+        request: Request<ConvertRequest>,
+    ) -> Result<Response<ConvertResponse>, Status> {
+        if let Some(status) = self.disabled("Convert") {
+            return Err(status);
+        }
+        let sample = self.sampler.begin("Convert", &request);
+        let result = self.with_request_timeout("Convert", self.handle_convert(request)).await;
+        if let Some(sample) = sample {
+            sample.finish("Convert", &result);
+        }
+        result
+    }
+
+    async fn get_metrics(
+        &self,
+        request: Request<GetMetricsRequest>,
+    ) -> Result<Response<GetMetricsResponse>, Status> {
+        if let Some(status) = self.disabled("GetMetrics") {
+            return Err(status);
+        }
+        let sample = self.sampler.begin("GetMetrics", &request);
+        let result = self.with_request_timeout("GetMetrics", self.handle_get_metrics(request)).await;
+        if let Some(sample) = sample {
+            sample.finish("GetMetrics", &result);
+        }
+        result
+    }
+
+    async fn subscribe_balance(
+        &self,
+        request: Request<SubscribeBalanceRequest>,
+    ) -> Result<Response<Self::SubscribeBalanceStream>, Status> {
+        if let Some(status) = self.disabled("SubscribeBalance") {
+            return Err(status);
+        }
+        let sample = self.sampler.begin("SubscribeBalance", &request);
+        let result = self.with_request_timeout("SubscribeBalance", self.handle_subscribe_balance(request)).await;
+        if let Some(sample) = sample {
+            sample.finish("SubscribeBalance", &result);
+        }
+        result
+    }
+}
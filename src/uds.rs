@@ -0,0 +1,31 @@
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::FileTypeExt;
+use tokio::net::UnixListener;
+
+// Bind a Unix domain socket at `path`, replacing a socket left behind by a
+// previous run. Refuses to remove anything that isn't a socket.
+pub fn bind(path: &str) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            log::warn!("Removing stale socket {}", path);
+            fs::remove_file(path)?;
+        }
+        Ok(_) => {
+            return Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("UDS_PATH {} exists and is not a socket", path),
+            ))
+        }
+        Err(error) if error.kind() == ErrorKind::NotFound => {}
+        Err(error) => return Err(error),
+    }
+    UnixListener::bind(path)
+}
+
+// Remove the socket file once the server has stopped
+pub fn cleanup(path: &str) {
+    if let Err(error) = fs::remove_file(path) {
+        log::warn!("Failed to remove socket {}: {}", path, error);
+    }
+}
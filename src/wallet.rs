@@ -0,0 +1,482 @@
+use regex::Regex;
+use std::env;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use tokio::process::Command as TokioCommand;
+use tokio::sync::watch;
+use tokio::time::{timeout_at, Instant};
+
+use crate::config::Config;
+
+pub const WALLET_BIN: &str = "nockchain-wallet";
+
+// Passed through from our environment when WALLET_CLEAR_ENV is on.
+// PATH is needed to resolve the wallet binary, HOME for its data directory.
+pub const WALLET_ENV_VARS: &[&str] = &["PATH", "HOME"];
+
+// Everything that can go wrong asking nockchain-wallet for a balance
+#[derive(Debug)]
+pub enum WalletError {
+    MissingSocket,
+    NotFound,
+    Timeout,
+    Spawn(std::io::Error),
+    Failed { status: ExitStatus, stderr: String },
+    Parse(String),
+    // Killed, or never started, because the server is shutting down
+    Terminated,
+}
+
+impl std::fmt::Display for WalletError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WalletError::MissingSocket => write!(f, "Missing NOCKCHAIN_SOCKET environment variable"),
+            WalletError::NotFound => write!(f, "balance support unavailable: wallet binary not found"),
+            WalletError::Timeout => write!(f, "Command timed out"),
+            WalletError::Spawn(error) => write!(f, "Server error: {}", error),
+            // stderr is logged, and only sent to clients with DEBUG_ERRORS
+            WalletError::Failed { status, .. } => write!(f, "Command execution failed ({})", status),
+            WalletError::Parse(error) => write!(f, "Parsing error: {}", error),
+            WalletError::Terminated => write!(f, "Server is shutting down"),
+        }
+    }
+}
+
+// Counts of wallet invocations by outcome, exposed through GetMetrics
+#[derive(Debug, Default)]
+pub struct WalletMetrics {
+    success: AtomicU64,
+    timeout: AtomicU64,
+    non_zero_exit: AtomicU64,
+    parse_failure: AtomicU64,
+    spawn_failure: AtomicU64,
+}
+
+impl WalletMetrics {
+    // A missing socket never reaches the wallet and a terminated call never
+    // finished, so neither is counted
+    pub fn record<T>(&self, result: &Result<T, WalletError>) {
+        let counter = match result {
+            Ok(_) => &self.success,
+            Err(WalletError::Timeout) => &self.timeout,
+            Err(WalletError::Failed { .. }) => &self.non_zero_exit,
+            Err(WalletError::Parse(_)) => &self.parse_failure,
+            Err(WalletError::NotFound | WalletError::Spawn(_)) => &self.spawn_failure,
+            Err(WalletError::MissingSocket | WalletError::Terminated) => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Track a call that its handler may be dropped in the middle of
+    pub fn begin(&self, deadline: Option<Instant>) -> PendingCall<'_> {
+        PendingCall { metrics: self, deadline, finished: false }
+    }
+
+    pub fn snapshot(&self) -> Vec<(&'static str, u64)> {
+        vec![
+            ("success", self.success.load(Ordering::Relaxed)),
+            ("timeout", self.timeout.load(Ordering::Relaxed)),
+            ("non_zero_exit", self.non_zero_exit.load(Ordering::Relaxed)),
+            ("parse_failure", self.parse_failure.load(Ordering::Relaxed)),
+            ("spawn_failure", self.spawn_failure.load(Ordering::Relaxed)),
+        ]
+    }
+}
+
+// A wallet call in flight. REQUEST_TIMEOUT_SECS and tonic's own client
+// deadline drop the handler, and the call with it, before `record` could run.
+// A call dropped unfinished once `deadline` has passed is counted as a
+// timeout; dropped earlier, the client went away and nothing is counted.
+#[derive(Debug)]
+pub struct PendingCall<'a> {
+    metrics: &'a WalletMetrics,
+    deadline: Option<Instant>,
+    finished: bool,
+}
+
+impl PendingCall<'_> {
+    pub fn finish<T>(mut self, result: &Result<T, WalletError>) {
+        self.finished = true;
+        self.metrics.record(result);
+    }
+}
+
+impl Drop for PendingCall<'_> {
+    fn drop(&mut self) {
+        if !self.finished && self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            self.metrics.timeout.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// Wallet subprocesses in flight. At the end of the shutdown grace period
+// terminate_all kills the ones still running and refuses new ones.
+#[derive(Debug)]
+pub struct WalletProcesses {
+    running: AtomicUsize,
+    terminate: watch::Sender<bool>,
+}
+
+impl Default for WalletProcesses {
+    fn default() -> Self {
+        WalletProcesses { running: AtomicUsize::new(0), terminate: watch::Sender::new(false) }
+    }
+}
+
+// Counts one running wallet until dropped
+struct Running<'a>(&'a WalletProcesses);
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.0.running.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl WalletProcesses {
+    // Returns how many wallets were still running
+    pub fn terminate_all(&self) -> usize {
+        self.terminate.send_replace(true);
+        self.running.load(Ordering::Relaxed)
+    }
+
+    // Resolves once terminate_all has been called
+    pub async fn terminated(&self) {
+        let mut terminate = self.terminate.subscribe();
+        let _ = terminate.wait_for(|terminate| *terminate).await;
+    }
+
+    fn start(&self) -> Option<Running<'_>> {
+        if *self.terminate.borrow() {
+            return None;
+        }
+        self.running.fetch_add(1, Ordering::Relaxed);
+        Some(Running(self))
+    }
+}
+
+// Look for the wallet on PATH, as the spawn will. Used to warn at startup.
+pub fn find_wallet_binary() -> Option<PathBuf> {
+    let path = env::var_os("PATH")?;
+    env::split_paths(&path)
+        .map(|dir| dir.join(WALLET_BIN))
+        .find(|candidate| candidate.is_file())
+}
+
+// How a recognized line contributes to the balance
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormatKind {
+    // One line per note; all matches are summed
+    Note,
+    // A single summary line; replaces any per-note sum
+    Total,
+}
+
+// A line format some wallet version prints. Patterns match the trimmed,
+// lowercased line and capture the raw asset amount.
+#[derive(Debug)]
+pub struct AssetFormat {
+    pub name: &'static str,
+    pub kind: FormatKind,
+    pub pattern: &'static str,
+}
+
+pub const ASSET_FORMATS: &[AssetFormat] = &[
+    AssetFormat { name: "assets", kind: FormatKind::Note, pattern: r"^- assets: (\d+)\s*$" },
+    AssetFormat { name: "balance", kind: FormatKind::Total, pattern: r"^balance: (\d+)\s*$" },
+    AssetFormat { name: "total", kind: FormatKind::Total, pattern: r"^total: (\d+)\s*$" },
+];
+
+// The format used when WALLET_OUTPUT_FORMATS is unset
+pub const DEFAULT_ASSET_FORMATS: &[&str] = &["assets"];
+
+pub fn find_asset_format(name: &str) -> Option<&'static AssetFormat> {
+    ASSET_FORMATS.iter().find(|format| format.name == name)
+}
+
+// Names the note that the following per-note assets line belongs to.
+// Matched against the original line since note names are case-sensitive.
+const NOTE_NAME_PATTERN: &str = r"(?i)^- name: (.+?)\s*$";
+
+// A single note from the wallet listing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalletNote {
+    // Empty when the wallet printed no name line for the note
+    pub name: String,
+    pub assets: u64,
+}
+
+// Parsed wallet output: the total, plus the per-note lines it was summed from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalletBalance {
+    pub total_assets: u64,
+    pub notes: Vec<WalletNote>,
+}
+
+// Function to parse nockchain-wallet output and sum all assets.
+// Each line is tried against `formats` in order and the first match wins.
+pub fn parse_nockchain_output(output: &str, formats: &[&'static AssetFormat]) -> Result<WalletBalance, String> {
+    if output.trim().is_empty() {
+        log::error!("Empty command output");
+        return Err("Empty command output".to_string());
+    }
+
+    log::debug!("Raw output length: {} bytes", output.len());
+    let patterns = formats
+        .iter()
+        .map(|format| {
+            Regex::new(format.pattern)
+                .map(|re| (*format, re))
+                .map_err(|e| format!("Regex error: {}", e))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let name_re = Regex::new(NOTE_NAME_PATTERN).map_err(|e| format!("Regex error: {}", e))?;
+    let mut total_assets: u64 = 0;
+    let mut notes = Vec::new();
+    let mut pending_name = None;
+    let mut reported_total = None;
+
+    for line in output.lines() {
+        let line = line.trim();
+        log::debug!("Processing line: {}", line);
+
+        // Skip empty lines and log messages
+        if line.is_empty() || line.contains("\u{001b}") {
+            log::debug!("Skipped line: {}", line);
+            continue;
+        }
+
+        if let Some(name) = name_re.captures(line).and_then(|c| c.get(1)) {
+            pending_name = Some(name.as_str().to_string());
+            continue;
+        }
+
+        let line = line.to_lowercase();
+        let matched = patterns
+            .iter()
+            .find_map(|(format, re)| re.captures(&line).and_then(|c| c.get(1)).map(|m| (*format, m.as_str())));
+        if let Some((format, asset_str)) = matched {
+            let assets: u64 = asset_str.parse().map_err(|e| format!("Failed to parse assets: {}", e))?;
+            log::info!("Found assets: {} (format: {})", assets, format.name);
+            match format.kind {
+                FormatKind::Note => {
+                    total_assets = total_assets
+                        .checked_add(assets)
+                        .ok_or_else(|| "Total assets overflow u64".to_string())?;
+                    notes.push(WalletNote { name: pending_name.take().unwrap_or_default(), assets });
+                }
+                FormatKind::Total => reported_total = Some(assets),
+            }
+        }
+    }
+
+    if let Some(total) = reported_total {
+        if !notes.is_empty() && total != total_assets {
+            log::warn!("Wallet reported total {} but notes sum to {}, using the total", total, total_assets);
+        }
+        log::info!("Total assets reported: {}", total);
+        return Ok(WalletBalance { total_assets: total, notes });
+    }
+
+    log::info!("Total assets summed: {}, Number of assets found: {}", total_assets, notes.len());
+    Ok(WalletBalance { total_assets, notes })
+}
+
+// Wallet stdout as text. Strict mode rejects invalid UTF-8 instead of
+// replacing it, so a mangled digit can't slip into an amount unnoticed.
+fn decode_stdout(stdout: Vec<u8>, strict: bool) -> Result<String, WalletError> {
+    match String::from_utf8(stdout) {
+        Ok(text) => Ok(text),
+        Err(error) if strict => {
+            log::error!("Wallet output is not valid UTF-8: {}", error.utf8_error());
+            Err(WalletError::Parse(format!("Wallet output is not valid UTF-8: {}", error.utf8_error())))
+        }
+        Err(error) => {
+            log::warn!("Wallet output is not valid UTF-8, replacing invalid bytes: {}", error.utf8_error());
+            Ok(String::from_utf8_lossy(error.as_bytes()).into_owned())
+        }
+    }
+}
+
+fn list_notes_command(config: &Config, socket_path: &str, pubkey: &str) -> TokioCommand {
+    let mut command = TokioCommand::new(WALLET_BIN);
+    if config.wallet_clear_env {
+        command.env_clear();
+        for name in WALLET_ENV_VARS {
+            if let Some(value) = env::var_os(name) {
+                command.env(name, value);
+            }
+        }
+    }
+    command
+        // kill_on_drop reaps the wallet if we time out or the client goes away
+        .kill_on_drop(true)
+        .env("RUST_LOG", "error")
+        .arg("--nockchain-socket")
+        .arg(socket_path)
+        .arg("list-notes-by-pubkey")
+        .arg(pubkey);
+    command
+}
+
+// Run `nockchain-wallet list-notes-by-pubkey` and return the parsed balance
+pub async fn query_balance(
+    config: &Config,
+    processes: &WalletProcesses,
+    pubkey: &str,
+    deadline: Instant,
+) -> Result<WalletBalance, WalletError> {
+    let socket_path = config.nockchain_socket.as_deref().ok_or_else(|| {
+        log::error!("Missing NOCKCHAIN_SOCKET environment variable");
+        WalletError::MissingSocket
+    })?;
+
+    let Some(_running) = processes.start() else {
+        log::warn!("Not starting {} during shutdown", WALLET_BIN);
+        return Err(WalletError::Terminated);
+    };
+    // Dropping the output future on terminate kills the wallet (kill_on_drop)
+    let started = Instant::now();
+    let run = timeout_at(deadline, list_notes_command(config, socket_path, pubkey).output());
+    let output = tokio::select! {
+        output = run => output,
+        _ = processes.terminated() => {
+            log::warn!("Killed {} for pubkey {} on shutdown", WALLET_BIN, pubkey);
+            return Err(WalletError::Terminated);
+        }
+    };
+    let output = output
+        .map_err(|_| {
+            log::error!("Command timed out after {:?}", started.elapsed());
+            WalletError::Timeout
+        })?
+        .map_err(|error| {
+            if error.kind() == ErrorKind::NotFound {
+                log::error!("{} not found on PATH", WALLET_BIN);
+                return WalletError::NotFound;
+            }
+            log::error!("Command error: {}", error);
+            WalletError::Spawn(error)
+        })?;
+
+    log::info!("Command executed, status: {}", output.status);
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        log::error!("Command failed: stderr={}", stderr);
+        return Err(WalletError::Failed { status: output.status, stderr });
+    }
+
+    let stdout = decode_stdout(output.stdout, config.wallet_utf8_strict)?;
+    log::debug!("Raw command output: {}", stdout);
+    parse_nockchain_output(&stdout, &config.wallet_formats).map_err(|error| {
+        log::error!("Parsing error: {}", error);
+        WalletError::Parse(error)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn formats(names: &[&str]) -> Vec<&'static AssetFormat> {
+        names.iter().map(|name| find_asset_format(name).unwrap()).collect()
+    }
+
+    fn note(name: &str, assets: u64) -> WalletNote {
+        WalletNote { name: name.to_string(), assets }
+    }
+
+    #[test]
+    fn sums_per_note_assets() {
+        let output = "- name: [a]\n- assets: 65536\n\n- name: [b]\n- Assets: 32768  \n";
+        let balance = parse_nockchain_output(output, &formats(&["assets"])).unwrap();
+        assert_eq!(balance.total_assets, 98304);
+        assert_eq!(balance.notes, vec![note("[a]", 65536), note("[b]", 32768)]);
+    }
+
+    #[test]
+    fn summary_formats_give_the_same_total_as_notes() {
+        let all = formats(&["assets", "balance", "total"]);
+        let from_notes = parse_nockchain_output("- assets: 65536\n- assets: 32768\n", &all).unwrap();
+        let from_balance = parse_nockchain_output("Balance: 98304\n", &all).unwrap();
+        let from_total = parse_nockchain_output("total: 98304\n", &all).unwrap();
+        assert_eq!(from_notes.total_assets, 98304);
+        assert_eq!(from_balance.total_assets, 98304);
+        assert_eq!(from_total.total_assets, 98304);
+        assert!(from_total.notes.is_empty());
+    }
+
+    #[test]
+    fn reported_total_overrides_note_sum() {
+        let output = "- assets: 10\n- assets: 20\ntotal: 25\n";
+        let balance = parse_nockchain_output(output, &formats(&["assets", "total"])).unwrap();
+        assert_eq!(balance.total_assets, 25);
+        assert_eq!(balance.notes.len(), 2);
+    }
+
+    #[test]
+    fn unselected_formats_are_ignored() {
+        let output = "- assets: 10\nbalance: 99\n";
+        assert_eq!(parse_nockchain_output(output, &formats(&["assets"])).unwrap().total_assets, 10);
+    }
+
+    #[test]
+    fn name_applies_to_the_next_assets_line() {
+        // Other fields may sit between a note's name and its assets
+        let output = "- name: [a]\n- source: somewhere\n- assets: 5\n- assets: 6\n";
+        let balance = parse_nockchain_output(output, &formats(&["assets"])).unwrap();
+        assert_eq!(balance.notes, vec![note("[a]", 5), note("", 6)]);
+
+        // A later name replaces one that never got an assets line
+        let output = "- name: [orphan]\n- name: [b]\n- assets: 7\n";
+        let balance = parse_nockchain_output(output, &formats(&["assets"])).unwrap();
+        assert_eq!(balance.notes, vec![note("[b]", 7)]);
+    }
+
+    #[test]
+    fn empty_or_overflowing_output_is_an_error() {
+        assert!(parse_nockchain_output("  \n", &formats(&["assets"])).is_err());
+        let output = format!("- assets: {}\n- assets: 1\n", u64::MAX);
+        assert!(parse_nockchain_output(&output, &formats(&["assets"])).is_err());
+    }
+
+    fn counts(metrics: &WalletMetrics) -> Vec<u64> {
+        metrics.snapshot().into_iter().map(|(_, count)| count).collect()
+    }
+
+    #[test]
+    fn each_outcome_increments_its_counter() {
+        use std::os::unix::process::ExitStatusExt;
+        let metrics = WalletMetrics::default();
+        metrics.record(&Ok(()));
+        assert_eq!(counts(&metrics), [1, 0, 0, 0, 0]);
+        metrics.record::<()>(&Err(WalletError::Timeout));
+        assert_eq!(counts(&metrics), [1, 1, 0, 0, 0]);
+        let failed = WalletError::Failed { status: ExitStatus::from_raw(1 << 8), stderr: String::new() };
+        metrics.record::<()>(&Err(failed));
+        assert_eq!(counts(&metrics), [1, 1, 1, 0, 0]);
+        metrics.record::<()>(&Err(WalletError::Parse("bad".to_string())));
+        assert_eq!(counts(&metrics), [1, 1, 1, 1, 0]);
+        metrics.record::<()>(&Err(WalletError::NotFound));
+        metrics.record::<()>(&Err(WalletError::Spawn(ErrorKind::PermissionDenied.into())));
+        assert_eq!(counts(&metrics), [1, 1, 1, 1, 2]);
+        // Neither reached a finished wallet
+        metrics.record::<()>(&Err(WalletError::MissingSocket));
+        metrics.record::<()>(&Err(WalletError::Terminated));
+        assert_eq!(counts(&metrics), [1, 1, 1, 1, 2]);
+    }
+
+    #[test]
+    fn dropped_calls_count_as_timeouts_only_past_their_deadline() {
+        let metrics = WalletMetrics::default();
+        metrics.begin(Some(Instant::now())).finish(&Ok(()));
+        assert_eq!(counts(&metrics), [1, 0, 0, 0, 0]);
+        drop(metrics.begin(Some(Instant::now())));
+        assert_eq!(counts(&metrics), [1, 1, 0, 0, 0]);
+        // The client went away before any deadline
+        drop(metrics.begin(Some(Instant::now() + std::time::Duration::from_secs(60))));
+        drop(metrics.begin(None));
+        assert_eq!(counts(&metrics), [1, 1, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,548 @@
+// End-to-end tests: start the real server binary against a fake `nockchain-wallet`
+// and call it over a real gRPC channel. Run with `cargo test --features client`.
+
+use std::fs;
+use std::net::TcpListener;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use hyper_util::rt::TokioIo;
+use prost::Message;
+use tokio::net::UnixStream;
+use tokio::time::{sleep, Duration};
+use tonic::transport::{Channel, Endpoint, Uri};
+use tonic::Code;
+use tower::service_fn;
+
+pub mod nockchain {
+    tonic::include_proto!("nockchain");
+}
+
+use nockchain::nockchain_service_client::NockchainServiceClient;
+use nockchain::{
+    convert_request, ConvertRequest, GetBalanceRequest, GetMetricsRequest, GetServerInfoRequest,
+    SubscribeBalanceRequest,
+};
+
+// Prints two notes for any pubkey, except: `fail` exits non-zero, `empty`
+// prints nothing, `slow` writes its pid to slow.pid and hangs, `watch` prints
+// balance.txt from the server's working directory so tests can change the
+// balance, `huge` prints a balance past f64's exact range, `env` prints one
+// note per environment variable name apart from the PWD the shell exports
+// itself, and `badutf8` prints a note whose name isn't valid UTF-8.
+const FAKE_WALLET: &str = r#"#!/bin/sh
+case "$4" in
+  fail) echo "wallet exploded" >&2; exit 1 ;;
+  empty) ;;
+  slow) echo $$ > slow.pid; exec sleep 10 ;;
+  watch) cat balance.txt ;;
+  huge) printf -- '- assets: 9007199254740993\n' ;;
+  env) env | cut -d= -f1 | grep -vx PWD | sort | while read -r name; do printf -- '- name: %s\n- assets: 1\n' "$name"; done ;;
+  badutf8) printf -- '- name: [first=\377]\n- assets: 65536\n' ;;
+  *) printf -- '- name: [first=Abc last=Def]\n- assets: 65536\n- name: [first=Ghi last=Jkl]\n- assets: 32768\n' ;;
+esac
+"#;
+
+struct TestServer {
+    child: Child,
+    dir: PathBuf,
+    port: u16,
+}
+
+impl TestServer {
+    fn start(name: &str, envs: &[(&str, &str)]) -> Self {
+        let dir = std::env::temp_dir().join(format!("nockchain-rpc-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let wallet = dir.join("nockchain-wallet");
+        fs::write(&wallet, FAKE_WALLET).unwrap();
+        fs::set_permissions(&wallet, fs::Permissions::from_mode(0o755)).unwrap();
+
+        // Grab an ephemeral port, then hand it to the server
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let path = format!("{}:{}", dir.display(), std::env::var("PATH").unwrap_or_default());
+
+        let child = Command::new(env!("CARGO_BIN_EXE_nockchain-rpc"))
+            // Run from the temp dir so a developer's .env isn't picked up
+            .current_dir(&dir)
+            // A developer's RUST_LOG would change what the server logs
+            .env_remove("RUST_LOG")
+            .env("PATH", path)
+            .env("PORT", port.to_string())
+            .env("NOCKCHAIN_SOCKET", dir.join("fake.sock"))
+            .envs(envs.iter().copied())
+            .stdout(Stdio::null())
+            .stderr(fs::File::create(dir.join("server.log")).unwrap())
+            .spawn()
+            .unwrap();
+
+        TestServer { child, dir, port }
+    }
+
+    async fn client(&self) -> NockchainServiceClient<Channel> {
+        let url = format!("http://127.0.0.1:{}", self.port);
+        for _ in 0..100 {
+            if let Ok(client) = NockchainServiceClient::connect(url.clone()).await {
+                return client;
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+        panic!("server on port {} never became reachable", self.port);
+    }
+
+    // Everything the server has logged so far
+    fn log(&self) -> String {
+        fs::read_to_string(self.dir.join("server.log")).unwrap_or_default()
+    }
+
+    // Connect over the Unix socket at `path` inside the server's temp dir
+    async fn uds_client(&self, path: &str) -> NockchainServiceClient<Channel> {
+        let socket = self.dir.join(path);
+        for _ in 0..100 {
+            let socket = socket.clone();
+            // The URI is required but unused, the connector always dials the socket
+            let connector = service_fn(move |_: Uri| {
+                let socket = socket.clone();
+                async move { Ok::<_, std::io::Error>(TokioIo::new(UnixStream::connect(socket).await?)) }
+            });
+            if let Ok(channel) = Endpoint::from_static("http://localhost").connect_with_connector(connector).await {
+                return NockchainServiceClient::new(channel);
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+        panic!("server on {} never became reachable", socket.display());
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn balance_request(pubkey: &str) -> GetBalanceRequest {
+    GetBalanceRequest { pubkey: pubkey.to_string(), at_height: None, include_notes: false }
+}
+
+#[tokio::test]
+async fn get_balance_sums_wallet_notes() {
+    let server = TestServer::start("balance", &[]);
+    let mut client = server.client().await;
+
+    let response = client.get_balance(balance_request("somepubkey")).await.unwrap().into_inner();
+    assert_eq!(response.balance, 1.5);
+    assert_eq!(response.raw_assets, 98304);
+    assert!(!response.precision_lossy);
+    assert!(response.notes.is_empty());
+
+    let request = GetBalanceRequest { include_notes: true, ..balance_request("somepubkey") };
+    let response = client.get_balance(request).await.unwrap().into_inner();
+    let notes: Vec<(&str, u64)> = response.notes.iter().map(|note| (note.name.as_str(), note.assets)).collect();
+    assert_eq!(notes, vec![("[first=Abc last=Def]", 65536), ("[first=Ghi last=Jkl]", 32768)]);
+}
+
+#[tokio::test]
+async fn get_balance_flags_precision_loss() {
+    let server = TestServer::start("precision", &[]);
+    let mut client = server.client().await;
+
+    let response = client.get_balance(balance_request("huge")).await.unwrap().into_inner();
+    assert_eq!(response.raw_assets, 9_007_199_254_740_993);
+    assert!(response.precision_lossy);
+
+    let response = client.get_balance(balance_request("somepubkey")).await.unwrap().into_inner();
+    assert!(!response.precision_lossy);
+}
+
+#[tokio::test]
+async fn rounded_balances_are_flagged_when_inexact() {
+    let server = TestServer::start("rounding-lossy", &[("BALANCE_ROUNDING", "truncate")]);
+    let mut client = server.client().await;
+
+    // 1.5 nocks survives rounding to 8 decimals exactly
+    let response = client.get_balance(balance_request("somepubkey")).await.unwrap().into_inner();
+    assert_eq!((response.balance, response.precision_lossy), (1.5, false));
+
+    // One asset is 0.0000152587890625 nocks, which truncates
+    fs::write(server.dir.join("balance.txt"), "- assets: 1\n").unwrap();
+    let response = client.get_balance(balance_request("watch")).await.unwrap().into_inner();
+    assert_eq!(response.balance, 0.00001525);
+    assert_eq!(response.raw_assets, 1);
+    assert!(response.precision_lossy);
+}
+
+#[tokio::test]
+async fn get_balance_reports_wallet_errors() {
+    let server = TestServer::start("balance-errors", &[]);
+    let mut client = server.client().await;
+
+    let status = client.get_balance(balance_request("fail")).await.unwrap_err();
+    assert_eq!(status.code(), Code::Internal);
+    assert!(!status.message().contains("wallet exploded"), "{}", status.message());
+
+    let status = client.get_balance(balance_request("empty")).await.unwrap_err();
+    assert_eq!(status.code(), Code::Internal);
+
+    let request = GetBalanceRequest { at_height: Some(10), ..balance_request("somepubkey") };
+    let status = client.get_balance(request).await.unwrap_err();
+    assert_eq!(status.code(), Code::Unimplemented);
+
+    let metrics = client.get_metrics(GetMetricsRequest {}).await.unwrap().into_inner();
+    assert_eq!(metrics.wallet_outcomes["non_zero_exit"], 1);
+    assert_eq!(metrics.wallet_outcomes["parse_failure"], 1);
+    assert_eq!(metrics.wallet_outcomes["success"], 0);
+
+    client.get_balance(balance_request("somepubkey")).await.unwrap();
+    let metrics = client.get_metrics(GetMetricsRequest {}).await.unwrap().into_inner();
+    assert_eq!(metrics.wallet_outcomes["success"], 1);
+    assert_eq!(metrics.wallet_outcomes["timeout"], 0);
+}
+
+#[tokio::test]
+async fn subscribe_balance_streams_changes() {
+    let server = TestServer::start("subscribe", &[("BALANCE_POLL_SECS", "1")]);
+    let mut client = server.client().await;
+    fs::write(server.dir.join("balance.txt"), "- assets: 65536\n").unwrap();
+
+    let request = SubscribeBalanceRequest { pubkey: "watch".to_string(), include_notes: false };
+    let mut stream = client.subscribe_balance(request).await.unwrap().into_inner();
+    let first = stream.message().await.unwrap().unwrap();
+    assert_eq!(first.raw_assets, 65536);
+
+    // Unchanged polls send nothing, so the next message is the new balance
+    fs::write(server.dir.join("balance.txt"), "- assets: 131072\n").unwrap();
+    let second = tokio::time::timeout(Duration::from_secs(5), stream.message()).await.unwrap().unwrap().unwrap();
+    assert_eq!(second.raw_assets, 131072);
+    assert_eq!(second.balance, 2.0);
+
+    // A wallet error is sent as the stream's final status
+    fs::write(server.dir.join("balance.txt"), "").unwrap();
+    let status = tokio::time::timeout(Duration::from_secs(5), stream.message()).await.unwrap().unwrap_err();
+    assert_eq!(status.code(), Code::Internal);
+}
+
+#[tokio::test]
+async fn balance_streams_are_capped() {
+    let server = TestServer::start("stream-cap", &[("MAX_BALANCE_STREAMS", "2"), ("BALANCE_POLL_SECS", "1")]);
+    let mut client = server.client().await;
+    fs::write(server.dir.join("balance.txt"), "- assets: 65536\n").unwrap();
+    let request = || SubscribeBalanceRequest { pubkey: "watch".to_string(), include_notes: false };
+
+    let mut first = client.subscribe_balance(request()).await.unwrap().into_inner();
+    first.message().await.unwrap().unwrap();
+    let mut second = client.subscribe_balance(request()).await.unwrap().into_inner();
+    second.message().await.unwrap().unwrap();
+    let status = client.subscribe_balance(request()).await.unwrap_err();
+    assert_eq!(status.code(), Code::ResourceExhausted);
+
+    // Closing a stream frees its slot once the poller notices
+    drop(first);
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    let mut third = loop {
+        match client.subscribe_balance(request()).await {
+            Ok(response) => break response.into_inner(),
+            Err(status) if status.code() == Code::ResourceExhausted && std::time::Instant::now() < deadline => {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            Err(status) => panic!("stream slot was not freed: {}", status),
+        }
+    };
+    assert_eq!(third.message().await.unwrap().unwrap().raw_assets, 65536);
+}
+
+#[tokio::test]
+async fn invalid_utf8_is_replaced_unless_strict() {
+    let server = TestServer::start("utf8-lossy", &[]);
+    let mut client = server.client().await;
+    let request = GetBalanceRequest { include_notes: true, ..balance_request("badutf8") };
+    let response = client.get_balance(request).await.unwrap().into_inner();
+    assert_eq!(response.raw_assets, 65536);
+    assert_eq!(response.notes[0].name, "[first=\u{fffd}]");
+
+    let server = TestServer::start("utf8-strict", &[("WALLET_UTF8_STRICT", "true")]);
+    let mut client = server.client().await;
+    let status = client.get_balance(balance_request("badutf8")).await.unwrap_err();
+    assert_eq!(status.code(), Code::Internal);
+    assert!(status.message().contains("UTF-8"), "{}", status.message());
+}
+
+// Call GetBalance with a `grpc-timeout` header over plain HTTP/2. A tonic
+// channel would enforce the deadline itself and report its own CANCELLED, so
+// this is the only way to see the status the server sends.
+async fn get_balance_with_deadline(server: &TestServer, pubkey: &str, grpc_timeout: &str) -> Code {
+    let tcp = tokio::net::TcpStream::connect(("127.0.0.1", server.port)).await.unwrap();
+    let (client, connection) = h2::client::handshake(tcp).await.unwrap();
+    tokio::spawn(connection);
+    let request = http::Request::post(format!("http://127.0.0.1:{}/nockchain.NockchainService/GetBalance", server.port))
+        .header("content-type", "application/grpc")
+        .header("te", "trailers")
+        .header("grpc-timeout", grpc_timeout)
+        .body(())
+        .unwrap();
+    let (response, mut body) = client.ready().await.unwrap().send_request(request, false).unwrap();
+
+    // A length-prefixed, uncompressed message
+    let message = balance_request(pubkey).encode_to_vec();
+    let mut frame = vec![0];
+    frame.extend((message.len() as u32).to_be_bytes());
+    frame.extend(message);
+    body.send_data(frame.into(), true).unwrap();
+
+    let mut response = response.await.unwrap();
+    // Errors usually come as a trailers-only response, with the status in the headers
+    let status = match response.headers().get("grpc-status") {
+        Some(status) => status.clone(),
+        None => response.body_mut().trailers().await.unwrap().unwrap()["grpc-status"].clone(),
+    };
+    Code::from(status.to_str().unwrap().parse::<i32>().unwrap())
+}
+
+#[tokio::test]
+async fn client_deadline_bounds_the_wallet_call() {
+    let server = TestServer::start("client-deadline", &[]);
+    // Wait until it's listening
+    let mut client = server.client().await;
+    let started = std::time::Instant::now();
+    // Answered by our handler; tonic's own deadline would drop it with CANCELLED
+    let code = get_balance_with_deadline(&server, "slow", "500m").await;
+    assert_eq!(code, Code::DeadlineExceeded);
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    let metrics = client.get_metrics(GetMetricsRequest {}).await.unwrap().into_inner();
+    assert_eq!(metrics.wallet_outcomes["timeout"], 1);
+
+    // The wallet is killed at the client's deadline, not after COMMAND_TIMEOUT_SECS
+    let wallet_pid = fs::read_to_string(server.dir.join("slow.pid")).unwrap().trim().to_string();
+    for _ in 0..40 {
+        if !process_running(&wallet_pid) {
+            return;
+        }
+        sleep(Duration::from_millis(50)).await;
+    }
+    panic!("wallet outlived the client deadline");
+}
+
+#[tokio::test]
+async fn request_timeout_bounds_the_whole_rpc() {
+    let envs = [("REQUEST_TIMEOUT_SECS", "1"), ("COMMAND_TIMEOUT_SECS", "30")];
+    let server = TestServer::start("request-timeout", &envs);
+    let mut client = server.client().await;
+
+    let started = std::time::Instant::now();
+    let status = client.get_balance(balance_request("slow")).await.unwrap_err();
+    assert_eq!(status.code(), Code::DeadlineExceeded);
+    assert!(status.message().contains("request timeout"), "{}", status.message());
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    // The dropped wallet call still counts as a timeout
+    let metrics = client.get_metrics(GetMetricsRequest {}).await.unwrap().into_inner();
+    assert_eq!(metrics.wallet_outcomes["timeout"], 1);
+}
+
+#[test]
+fn print_descriptor_writes_the_service_descriptor() {
+    let path = std::env::temp_dir().join(format!("nockchain-rpc-descriptor-{}.pb", std::process::id()));
+    let status = Command::new(env!("CARGO_BIN_EXE_nockchain-rpc"))
+        .arg("print-descriptor")
+        .arg(&path)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let descriptor = fs::read(&path).unwrap();
+    let _ = fs::remove_file(&path);
+    assert!(!descriptor.is_empty());
+    assert!(descriptor.windows(b"NockchainService".len()).any(|window| window == b"NockchainService"));
+}
+
+#[tokio::test]
+async fn serves_over_a_unix_socket() {
+    // Relative to the server's working directory, its temp dir. The BOM and
+    // padding that editors leave in .env files are stripped.
+    let server = TestServer::start("uds", &[("UDS_PATH", "\u{feff} rpc.sock ")]);
+    let mut client = server.uds_client("rpc.sock").await;
+
+    let response = client.get_balance(balance_request("somepubkey")).await.unwrap().into_inner();
+    assert_eq!(response.raw_assets, 98304);
+}
+
+// Running, as opposed to exited or an unreaped zombie
+fn process_running(pid: &str) -> bool {
+    match fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        Ok(stat) => !stat.rsplit(')').next().unwrap_or_default().trim_start().starts_with('Z'),
+        Err(_) => false,
+    }
+}
+
+#[tokio::test]
+async fn shutdown_kills_running_wallets_after_the_grace_period() {
+    let envs = [("SHUTDOWN_GRACE_SECS", "1")];
+    let mut server = TestServer::start("shutdown", &envs);
+    let mut client = server.client().await;
+    let call = tokio::spawn(async move { client.get_balance(balance_request("slow")).await });
+
+    let pid_file = server.dir.join("slow.pid");
+    for _ in 0..100 {
+        if fs::read_to_string(&pid_file).is_ok_and(|pid| !pid.trim().is_empty()) {
+            break;
+        }
+        sleep(Duration::from_millis(50)).await;
+    }
+    let wallet_pid = fs::read_to_string(&pid_file).unwrap().trim().to_string();
+    assert!(process_running(&wallet_pid));
+
+    let started = std::time::Instant::now();
+    let killed = Command::new("kill").arg("-TERM").arg(server.child.id().to_string()).status().unwrap();
+    assert!(killed.success());
+
+    let status = call.await.unwrap().unwrap_err();
+    assert_eq!(status.code(), Code::Unavailable);
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert!(!process_running(&wallet_pid));
+
+    for _ in 0..100 {
+        if server.child.try_wait().unwrap().is_some() {
+            return;
+        }
+        sleep(Duration::from_millis(50)).await;
+    }
+    panic!("server did not exit after the grace period");
+}
+
+#[tokio::test]
+async fn debug_errors_includes_wallet_stderr() {
+    let server = TestServer::start("debug-errors", &[("DEBUG_ERRORS", "true")]);
+    let mut client = server.client().await;
+
+    let status = client.get_balance(balance_request("fail")).await.unwrap_err();
+    assert_eq!(status.code(), Code::Internal);
+    assert!(status.message().ends_with(": wallet exploded"), "{}", status.message());
+}
+
+#[tokio::test]
+async fn missing_wallet_binary_returns_unimplemented() {
+    // envs override the PATH that points at the fake wallet
+    let server = TestServer::start("no-wallet", &[("PATH", "/nonexistent")]);
+    let mut client = server.client().await;
+
+    let status = client.get_balance(balance_request("somepubkey")).await.unwrap_err();
+    assert_eq!(status.code(), Code::Unimplemented);
+    assert!(status.message().contains("wallet binary not found"), "{}", status.message());
+
+    let metrics = client.get_metrics(GetMetricsRequest {}).await.unwrap().into_inner();
+    assert_eq!(metrics.wallet_outcomes["spawn_failure"], 1);
+}
+
+async fn wallet_env_names(server: &TestServer) -> Vec<String> {
+    let mut client = server.client().await;
+    let request = GetBalanceRequest { include_notes: true, ..balance_request("env") };
+    let response = client.get_balance(request).await.unwrap().into_inner();
+    response.notes.into_iter().map(|note| note.name).collect()
+}
+
+#[tokio::test]
+async fn wallet_clear_env_passes_only_allowed_variables() {
+    let envs = [("WALLET_CLEAR_ENV", "true"), ("HOME", "/tmp"), ("LEAKY_SECRET", "hunter2")];
+    let server = TestServer::start("clear-env", &envs);
+    assert_eq!(wallet_env_names(&server).await, vec!["HOME", "PATH", "RUST_LOG"]);
+
+    let server = TestServer::start("inherit-env", &[("LEAKY_SECRET", "hunter2")]);
+    assert!(wallet_env_names(&server).await.contains(&"LEAKY_SECRET".to_string()));
+}
+
+#[tokio::test]
+async fn get_server_info_reports_version_and_features() {
+    let server = TestServer::start("server-info", &[("API_TOKEN", "secret")]);
+    let mut client = server.client().await;
+
+    let mut request = tonic::Request::new(GetServerInfoRequest {});
+    request.metadata_mut().insert("authorization", "Bearer secret".parse().unwrap());
+    let info = client.get_server_info(request).await.unwrap().into_inner();
+    assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+    assert!(info.features.contains(&"auth".to_string()));
+
+    let status = client.get_server_info(GetServerInfoRequest {}).await.unwrap_err();
+    assert_eq!(status.code(), Code::Unauthenticated);
+}
+
+#[tokio::test]
+async fn api_token_is_checked_on_every_call() {
+    let server = TestServer::start("auth", &[("API_TOKEN", "secret")]);
+    let mut client = server.client().await;
+
+    let with_token = |token: &str| {
+        let mut request = tonic::Request::new(balance_request("somepubkey"));
+        request.metadata_mut().insert("authorization", token.parse().unwrap());
+        request
+    };
+
+    let status = client.get_balance(with_token("Bearer wrong")).await.unwrap_err();
+    assert_eq!(status.code(), Code::Unauthenticated);
+
+    // The bare token is accepted as well as the Bearer form
+    let response = client.get_balance(with_token("secret")).await.unwrap().into_inner();
+    assert_eq!(response.raw_assets, 98304);
+}
+
+#[tokio::test]
+async fn convert_round_trips_over_the_wire() {
+    let server = TestServer::start("convert", &[]);
+    let mut client = server.client().await;
+
+    let request = ConvertRequest { value: Some(convert_request::Value::Nocks(0.5)) };
+    let response = client.convert(request).await.unwrap().into_inner();
+    assert_eq!(response.assets, 32768);
+
+    let status = client.convert(ConvertRequest { value: None }).await.unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+}
+
+#[tokio::test]
+async fn assets_per_nock_changes_the_divisor() {
+    let server = TestServer::start("assets-per-nock", &[("ASSETS_PER_NOCK", "32768")]);
+    let mut client = server.client().await;
+
+    let response = client.get_balance(balance_request("somepubkey")).await.unwrap().into_inner();
+    assert_eq!(response.raw_assets, 98304);
+    assert_eq!(response.balance, 3.0);
+
+    let request = ConvertRequest { value: Some(convert_request::Value::Nocks(0.5)) };
+    let response = client.convert(request).await.unwrap().into_inner();
+    assert_eq!(response.assets, 16384);
+}
+
+#[test]
+fn zero_assets_per_nock_is_a_config_error() {
+    let mut server = TestServer::start("zero-divisor", &[("ASSETS_PER_NOCK", "0")]);
+    let status = server.child.wait().unwrap();
+    assert!(!status.success());
+}
+
+#[tokio::test]
+async fn log_sample_rate_logs_one_in_n_requests() {
+    let server = TestServer::start("sampling", &[("LOG_SAMPLE_RATE", "3")]);
+    let mut client = server.client().await;
+
+    for assets in 0..9 {
+        let request = ConvertRequest { value: Some(convert_request::Value::Assets(assets)) };
+        client.convert(request).await.unwrap();
+    }
+    let log = server.log();
+    let samples = log.lines().filter(|line| line.contains("[sample") && line.contains(" ok in ")).count();
+    assert_eq!(samples, 3, "{}", log);
+    // Sampling alone doesn't switch on the per-request info logs
+    assert!(!log.contains("nockchain_rpc::service"), "{}", log);
+}
+
+#[tokio::test]
+async fn disabled_methods_return_unimplemented() {
+    let server = TestServer::start("enabled-methods", &[("ENABLED_METHODS", "GetServerInfo")]);
+    let mut client = server.client().await;
+
+    let status = client.get_balance(balance_request("somepubkey")).await.unwrap_err();
+    assert_eq!(status.code(), Code::Unimplemented);
+
+    let info = client.get_server_info(GetServerInfoRequest {}).await.unwrap().into_inner();
+    assert_eq!(info.enabled_methods, vec!["GetServerInfo".to_string()]);
+}